@@ -0,0 +1,71 @@
+// Copyright © 2022 Bart Massey
+// [This program is licensed under the "MIT License"]
+// Please see the file LICENSE in the source
+// distribution of this software for license terms.
+
+//! Throughput of sampler playback at each resampling
+//! quality, cost of starting a note, and throughput of
+//! whole-sample rate conversion.
+
+use std::f32::consts::PI;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use rustsy::*;
+
+// One second of a decaying tone at the default rate.
+fn tone() -> Vec<f32> {
+    let rate = DEFAULT_SAMPLE_RATE as f32;
+    (0..DEFAULT_SAMPLE_RATE)
+        .map(|i| {
+            let t = i as f32 / rate;
+            f32::exp(-2.0 * t) * f32::sin(2.0 * PI * 440.0 * t)
+        })
+        .collect()
+}
+
+fn playback(c: &mut Criterion) {
+    let buf = tone();
+    let n = 48_000;
+    let mut group = c.benchmark_group("playback");
+    group.throughput(Throughput::Elements(n as u64));
+    let qualities = [
+        ("linear", Quality::Linear),
+        ("cubic", Quality::Cubic),
+        ("sinc8", "sinc:8".parse().unwrap()),
+        ("sinc16", Quality::default()),
+        ("sinc64", "sinc:64:10".parse().unwrap()),
+    ];
+    for (name, quality) in qualities {
+        let sloop = Loop::new(&buf, DEFAULT_SAMPLE_RATE).with_quality(quality);
+        group.bench_function(BenchmarkId::from_parameter(name), |b| {
+            b.iter(|| {
+                sloop
+                    .iter_freq(523.25, DEFAULT_SAMPLE_RATE)
+                    .take(n)
+                    .sum::<f32>()
+            })
+        });
+    }
+    group.finish();
+}
+
+fn note_on(c: &mut Criterion) {
+    let buf = tone();
+    let sloop = Loop::new(&buf, DEFAULT_SAMPLE_RATE).with_quality("sinc:64:10".parse().unwrap());
+    c.bench_function("note-on", |b| {
+        b.iter(|| sloop.iter_freq(523.25, DEFAULT_SAMPLE_RATE).next())
+    });
+}
+
+fn convert(c: &mut Criterion) {
+    let buf = tone();
+    let mut group = c.benchmark_group("convert");
+    group.throughput(Throughput::Elements(buf.len() as u64));
+    group.bench_function("48000-44100", |b| {
+        b.iter(|| resample(&buf, DEFAULT_SAMPLE_RATE, 44_100))
+    });
+    group.finish();
+}
+
+criterion_group!(benches, playback, note_on, convert);
+criterion_main!(benches);
@@ -1,16 +1,182 @@
+use std::num::NonZeroUsize;
 use std::path::PathBuf;
 use structopt::StructOpt;
 
+use rustsy::{Curve, Quality, Retrigger, Steal, VelocityCurve};
+
 #[derive(Debug, StructOpt)]
 pub struct Opt {
-    #[structopt(short, long)]
-    pub keyboard: String,
+    /// MIDI keyboard to play from.
+    #[structopt(
+        short,
+        long,
+        required_unless_one = &["script", "midi-file", "list-devices"]
+    )]
+    pub keyboard: Option<String>,
+
+    /// List audio output devices and exit.
+    #[structopt(long)]
+    pub list_devices: bool,
+
+    /// Audio output device, as `[host:]name` or
+    /// `[host:]index` from `--list-devices`.
+    #[structopt(long)]
+    pub device: Option<String>,
+
+    /// Render to this WAV file instead of playing live.
+    #[structopt(long)]
+    pub render: Option<PathBuf>,
+
+    /// Sample rate in Hz. When playing live this is only a
+    /// preference: the device's rate is used if it differs.
+    #[structopt(long, default_value = "48000")]
+    pub rate: u32,
+
+    /// Script of timed key events to play.
+    #[structopt(long, conflicts_with_all = &["keyboard", "midi-file"])]
+    pub script: Option<PathBuf>,
+
+    /// Standard MIDI File to play.
+    #[structopt(long, conflicts_with = "keyboard")]
+    pub midi_file: Option<PathBuf>,
+
+    /// WAV samples to play. Give more than one to spread
+    /// them across the keyboard by their root keys.
+    #[structopt(long, number_of_values = 1)]
+    pub sampler: Vec<PathBuf>,
 
+    /// SFZ instrument to play.
+    #[structopt(long, conflicts_with = "sampler")]
+    pub sfz: Option<PathBuf>,
+
+    /// Channel of the sample file to play, counting from 0.
+    /// By default all channels are mixed.
+    #[structopt(long)]
+    pub channel: Option<usize>,
+
+    /// For `--sampler`, play the part of the sample after
+    /// the loop once the key is released.
     #[structopt(long)]
-    pub sampler: Option<PathBuf>,
+    pub release_tail: bool,
+
+    /// For `--sampler`, crossfade time in seconds at the
+    /// loop seam.
+    #[structopt(long, default_value = "0")]
+    pub crossfade: f32,
+
+    /// For `--sampler` and `--sfz`, interpolation between
+    /// samples: `linear`, `cubic`, or `sinc` with optional
+    /// tap count and Kaiser window beta, as in `sinc:32:9.5`.
+    #[structopt(long, default_value = "sinc")]
+    pub quality: Quality,
 
     #[structopt(long)]
     pub wave: Option<String>,
+
+    /// FM patch name, for `--wave fm`.
+    #[structopt(long, default_value = "epiano")]
+    pub patch: String,
+
+    /// Single-cycle WAV frames, for `--wave table`.
+    #[structopt(long)]
+    pub frames: Vec<PathBuf>,
+
+    /// Pulse duty cycle, for `--wave pulse`.
+    #[structopt(long, default_value = "0.5")]
+    pub duty: f32,
+
+    /// Pulse-width modulation LFO rate in Hz.
+    #[structopt(long, default_value = "0")]
+    pub pwm_rate: f32,
+
+    /// Pulse-width modulation depth.
+    #[structopt(long, default_value = "0")]
+    pub pwm_depth: f32,
+
+    /// Noise seed, for `--wave white`, `pink` or `brown`.
+    #[structopt(long)]
+    pub seed: Option<u64>,
+
+    /// Envelope segment shape: `lin`, `exp`, `log` or a
+    /// curvature.
+    #[structopt(long)]
+    pub curve: Option<Curve>,
+
+    /// Velocity curve: `fixed`, `lin`, `soft`, `hard` or
+    /// `exp`.
+    #[structopt(long, default_value = "lin")]
+    pub velocity_curve: VelocityCurve,
+
+    /// Attack stretch for soft notes: zero-velocity attack
+    /// is this much longer again.
+    #[structopt(long, default_value = "0")]
+    pub velocity_attack: f32,
+
+    /// Lowpass tracking for soft notes: zero-velocity
+    /// cutoff is this many octaves below 20 kHz.
+    #[structopt(long, default_value = "0")]
+    pub velocity_cutoff: f32,
+
+    /// Maximum number of notes sounding at once.
+    #[structopt(long, default_value = "32")]
+    pub polyphony: NonZeroUsize,
+
+    /// Note to steal past the polyphony limit: `oldest`,
+    /// `quietest`, `lowest`, `highest` or `released`.
+    #[structopt(long, default_value = "released")]
+    pub steal: Steal,
+
+    /// What to do when a sounding key is struck again:
+    /// `restart`, `layer` or `ignore`.
+    #[structopt(long, default_value = "restart")]
+    pub retrigger: Retrigger,
+
+    /// Output limiter threshold in dB relative to full scale.
+    #[structopt(long, default_value = "-1.0", allow_hyphen_values = true)]
+    pub limit_threshold: f32,
+
+    /// Output limiter ratio: `inf` for a brickwall limiter.
+    #[structopt(long, default_value = "inf", parse(try_from_str = parse_ratio))]
+    pub limit_ratio: f32,
+
+    /// Output limiter attack time in seconds.
+    #[structopt(long, default_value = "0.001", parse(try_from_str = parse_time))]
+    pub limit_attack: f32,
+
+    /// Output limiter release time in seconds.
+    #[structopt(long, default_value = "0.1", parse(try_from_str = parse_time))]
+    pub limit_release: f32,
+
+    /// Output limiter lookahead time in seconds.
+    #[structopt(long, default_value = "0.005", parse(try_from_str = parse_time))]
+    pub limit_lookahead: f32,
+
+    /// Use naive (aliasing) waveforms instead of band-limited ones.
+    #[structopt(long)]
+    pub raw: bool,
+}
+
+// Limiter ratio: at least 1, or `inf`.
+fn parse_ratio(s: &str) -> Result<f32, String> {
+    let ratio: f32 = s.parse().map_err(|e| format!("{}", e))?;
+    if ratio >= 1.0 {
+        Ok(ratio)
+    } else {
+        Err(format!("ratio {} is below 1", s))
+    }
+}
+
+// Time in seconds: finite and not negative.
+fn parse_time(s: &str) -> Result<f32, String> {
+    let time: f32 = s.parse().map_err(|e| format!("{}", e))?;
+    if time.is_finite() && time >= 0.0 {
+        Ok(time)
+    } else {
+        Err(format!(
+            "time {} is not a non-negative number of seconds",
+            s
+        ))
+    }
 }
 
 pub fn args() -> Opt {
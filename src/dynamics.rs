@@ -0,0 +1,278 @@
+// Copyright © 2022 Bart Massey
+// [This program is licensed under the "MIT License"]
+// Please see the file LICENSE in the source
+// distribution of this software for license terms.
+
+//! Output dynamics.
+//!
+//! The limiter delays its input by a short lookahead, so
+//! that the gain can be brought down smoothly *before* a
+//! peak arrives rather than after. Above the threshold the
+//! level is compressed by the ratio: an infinite ratio makes
+//! a brickwall limiter. Whatever gets past the gain stage is
+//! caught by a soft clipper, so that the output never leaves
+//! `-1.0..=1.0`.
+
+use std::collections::VecDeque;
+
+use crate::*;
+
+/// Level at which the soft clipper starts to bend.
+const CLIP_KNEE: f32 = 0.9;
+
+/// Lookahead compressor/limiter.
+#[derive(Debug, Clone)]
+pub struct Limiter {
+    /// Threshold as a linear level.
+    threshold: f32,
+    /// Compression ratio above the threshold.
+    ratio: f32,
+    /// Attack, release and lookahead times in seconds.
+    times: [f32; 3],
+    /// Sample rate.
+    rate: u32,
+    /// Per-sample smoothing coefficient when the gain is
+    /// falling.
+    attack: f32,
+    /// Per-sample smoothing coefficient when the gain is
+    /// rising.
+    release: f32,
+    /// Lookahead in samples.
+    lookahead: usize,
+    /// Delayed input samples.
+    delay: VecDeque<f32>,
+    /// Sliding minimum of the target gain over the
+    /// lookahead: (sample count, gain), increasing gain.
+    targets: VecDeque<(u64, f32)>,
+    /// Count of input samples.
+    count: u64,
+    /// Current smoothed gain.
+    gain: f32,
+}
+
+// One-pole smoothing coefficient for the given time
+// constant in seconds at the given sample rate.
+fn coefficient(time: f32, rate: u32) -> f32 {
+    if time > 0.0 {
+        f32::exp(-1.0 / (time * rate as f32))
+    } else {
+        0.0
+    }
+}
+
+/// Bend levels above `CLIP_KNEE` smoothly toward full scale.
+/// Below the knee the signal is untouched, and the slope
+/// is continuous across it.
+pub fn soft_clip(x: f32) -> f32 {
+    let a = x.abs();
+    if a <= CLIP_KNEE {
+        return x;
+    }
+    let room = 1.0 - CLIP_KNEE;
+    x.signum() * (CLIP_KNEE + room * f32::tanh((a - CLIP_KNEE) / room))
+}
+
+impl Limiter {
+    /// Make a new limiter with a threshold of -1 dBFS, an
+    /// infinite ratio, 1 ms attack, 100 ms release and 5 ms
+    /// lookahead, running at the default sample rate.
+    pub fn new() -> Self {
+        let mut limiter = Self {
+            threshold: 1.0,
+            ratio: f32::INFINITY,
+            times: [0.001, 0.1, 0.005],
+            rate: DEFAULT_SAMPLE_RATE,
+            attack: 0.0,
+            release: 0.0,
+            lookahead: 0,
+            delay: VecDeque::new(),
+            targets: VecDeque::new(),
+            count: 0,
+            gain: 1.0,
+        };
+        limiter.tune();
+        limiter.with_threshold(-1.0)
+    }
+
+    // Work out the per-sample parameters from the times and
+    // the sample rate, and start over.
+    fn tune(&mut self) {
+        let [attack, release, lookahead] = self.times;
+        self.attack = coefficient(attack, self.rate);
+        self.release = coefficient(release, self.rate);
+        self.lookahead = (lookahead * self.rate as f32).round() as usize;
+        self.delay = VecDeque::from(vec![0.0; self.lookahead]);
+        self.targets = VecDeque::with_capacity(self.lookahead + 1);
+        self.count = 0;
+        self.gain = 1.0;
+    }
+
+    /// Change the sample rate. This resets the limiter.
+    pub fn set_rate(&mut self, rate: u32) {
+        self.rate = rate;
+        self.tune();
+    }
+
+    /// Set the threshold in dB relative to full scale.
+    pub fn with_threshold(mut self, db: f32) -> Self {
+        self.threshold = f32::powf(10.0, db / 20.0);
+        self
+    }
+
+    /// Set the compression ratio. Must be at least 1.
+    pub fn with_ratio(mut self, ratio: f32) -> Self {
+        assert!(ratio >= 1.0, "limiter ratio below 1");
+        self.ratio = ratio;
+        self
+    }
+
+    /// Set the attack time constant in seconds. This should
+    /// be well under the lookahead time, or the gain will
+    /// still be jumping down when peaks come out.
+    pub fn with_attack(mut self, time: f32) -> Self {
+        self.times[0] = time;
+        self.tune();
+        self
+    }
+
+    /// Set the release time constant in seconds.
+    pub fn with_release(mut self, time: f32) -> Self {
+        self.times[1] = time;
+        self.tune();
+        self
+    }
+
+    /// Set the lookahead time in seconds. The output is
+    /// delayed by this much.
+    pub fn with_lookahead(mut self, time: f32) -> Self {
+        self.times[2] = time;
+        self.tune();
+        self
+    }
+
+    /// Output delay in samples.
+    pub fn latency(&self) -> usize {
+        self.lookahead
+    }
+
+    /// Current gain reduction as a linear factor.
+    pub fn gain(&self) -> f32 {
+        self.gain
+    }
+
+    // Gain that brings the given level down to the
+    // compression curve.
+    fn target(&self, x: f32) -> f32 {
+        let a = x.abs();
+        if a <= self.threshold {
+            return 1.0;
+        }
+        // In the log domain, the excess over the threshold
+        // is divided by the ratio.
+        let excess = a / self.threshold;
+        excess.powf(1.0 / self.ratio) / excess
+    }
+
+    /// Take an input sample and return the output sample
+    /// from `lookahead` samples ago.
+    pub fn process(&mut self, x: f32) -> f32 {
+        // Keep the minimum target gain over the lookahead
+        // window at the front of the queue.
+        let target = self.target(x);
+        while let Some(&(_, g)) = self.targets.back() {
+            if g < target {
+                break;
+            }
+            self.targets.pop_back();
+        }
+        self.targets.push_back((self.count, target));
+        let oldest = self.count.saturating_sub(self.lookahead as u64);
+        while self.targets[0].0 < oldest {
+            self.targets.pop_front();
+        }
+        self.count += 1;
+        let target = self.targets[0].1;
+
+        let coeff = if target < self.gain {
+            self.attack
+        } else {
+            self.release
+        };
+        self.gain = target + coeff * (self.gain - target);
+
+        self.delay.push_back(x);
+        let y = self.delay.pop_front().unwrap();
+        // If the attack has not quite caught up by the time
+        // a peak comes out, take the last little step now.
+        self.gain = self.gain.min(self.target(y));
+        soft_clip(y * self.gain)
+    }
+}
+
+impl Default for Limiter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[test]
+// Check that quiet signals come through unchanged, just
+// delayed by the lookahead.
+fn test_limiter_transparent() {
+    let mut limiter = Limiter::new();
+    let input: Vec<f32> = (0..1000).map(|i| 0.5 * f32::sin(0.05 * i as f32)).collect();
+    let output: Vec<f32> = input.iter().map(|&x| limiter.process(x)).collect();
+    let n = limiter.lookahead;
+    assert!(n > 0);
+    assert!(output[..n].iter().all(|&y| y == 0.0));
+    for (x, y) in input.iter().zip(&output[n..]) {
+        assert!((x - y).abs() < 1.0e-6);
+    }
+
+    // The lookahead is a time, not a sample count.
+    limiter.set_rate(2 * DEFAULT_SAMPLE_RATE);
+    assert_eq!(2 * n, limiter.latency());
+}
+
+#[test]
+// Check that a sudden loud burst is held to the threshold
+// without relying on the clipper, and that the gain moves
+// smoothly.
+fn test_limiter_peak() {
+    let mut limiter = Limiter::new().with_threshold(-6.0);
+    let threshold = f32::powf(10.0, -6.0 / 20.0);
+    let mut gains = Vec::new();
+    let mut peak = 0.0f32;
+    for i in 0..DEFAULT_SAMPLE_RATE as usize / 2 {
+        let amp = if i < 1000 { 0.1 } else { 4.0 };
+        let y = limiter.process(amp * f32::sin(0.03 * i as f32));
+        peak = peak.max(y.abs());
+        gains.push(limiter.gain());
+    }
+    assert!(peak <= threshold * 1.01, "peak {}", peak);
+    // A stepped gain would drop by nearly 0.9 at once.
+    assert!(gains.windows(2).all(|w| (w[1] - w[0]).abs() < 0.05));
+}
+
+#[test]
+// Check that a finite ratio compresses rather than limits.
+fn test_limiter_ratio() {
+    let limiter = Limiter::new().with_threshold(-20.0).with_ratio(4.0);
+    // 20 dB over the threshold comes out 5 dB over.
+    let g = limiter.target(1.0);
+    let out = 20.0 * f32::log10(g);
+    assert!((out + 15.0).abs() < 1.0e-3, "{}", out);
+}
+
+#[test]
+// Check that the soft clipper is bounded, continuous and
+// transparent below its knee.
+fn test_soft_clip() {
+    assert_eq!(0.5, soft_clip(0.5));
+    assert_eq!(-CLIP_KNEE, soft_clip(-CLIP_KNEE));
+    assert!((soft_clip(CLIP_KNEE + 1.0e-4) - CLIP_KNEE - 1.0e-4).abs() < 1.0e-5);
+    for x in [1.0, 2.0, 100.0, f32::MAX] {
+        assert!(soft_clip(x) <= 1.0);
+        assert!(soft_clip(-x) >= -1.0);
+    }
+}
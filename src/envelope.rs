@@ -1,58 +1,312 @@
+use std::str::FromStr;
+
 use crate::*;
 
-/// Attack-Decay-Sustain-Release envelope parameters.
+/// Curvature used for `Curve::Exponential` and (negated)
+/// `Curve::Logarithmic`. The exponential segment gets about
+/// 99% of the way to its target in its time.
+const CURVATURE: f32 = 5.0;
+
+/// Shape of an envelope segment.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Curve {
+    /// Straight-line ramp.
+    Linear,
+    /// Exponential approach: fast at first, slowing as it
+    /// nears the target, like an analog envelope.
+    Exponential,
+    /// Mirror image of exponential: slow at first, speeding
+    /// up toward the target.
+    Logarithmic,
+    /// Adjustable curvature. Zero is linear; positive bends
+    /// like `Exponential`, negative like `Logarithmic`.
+    Curvature(f32),
+}
+
+impl Curve {
+    /// Fraction of the way from start level to target level
+    /// after fraction `x` of the segment time.
+    pub fn shape(self, x: f32) -> f32 {
+        let c = match self {
+            Curve::Linear => return x,
+            Curve::Exponential => CURVATURE,
+            Curve::Logarithmic => -CURVATURE,
+            Curve::Curvature(c) => c,
+        };
+        if c.abs() < 1.0e-3 {
+            return x;
+        }
+        (1.0 - f32::exp(-c * x)) / (1.0 - f32::exp(-c))
+    }
+}
+
+impl FromStr for Curve {
+    type Err = String;
+
+    /// Parse `lin`, `exp`, `log` or a curvature.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "lin" | "linear" => Ok(Curve::Linear),
+            "exp" | "exponential" => Ok(Curve::Exponential),
+            "log" | "logarithmic" => Ok(Curve::Logarithmic),
+            _ => s.parse().map(Curve::Curvature).map_err(|_| {
+                format!(
+                    "invalid envelope curve {}: use lin, exp, log or a number",
+                    s
+                )
+            }),
+        }
+    }
+}
+
+/// Attack-Decay-Sustain-Release envelope: see
+/// [Breakpoints::adsr].
+pub type ADSR = Breakpoints;
+
+/// One segment of a breakpoint envelope: a ramp from the
+/// current level to a target level.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Segment {
+    /// Ramp time in seconds.
+    pub time: f32,
+    /// Target level.
+    pub level: f32,
+    /// Ramp shape.
+    pub curve: Curve,
+}
+
+impl Segment {
+    /// Make a segment ramping to `level` over `time`
+    /// seconds with the given shape.
+    pub fn new(time: f32, level: f32, curve: Curve) -> Self {
+        Self { time, level, curve }
+    }
+}
+
+/// General breakpoint envelope parameters. The envelope
+/// starts at level 0, waits out its delay, and then runs
+/// through its segments. Breakpoint `i` is the end of
+/// segment `i`.
+///
+/// While the key is held, the envelope stops at the sustain
+/// breakpoint if there is one, or runs around the loop if
+/// there is one. On release, or when it runs out of
+/// segments, it runs through its release segments starting
+/// from whatever level it had reached.
 // XXX For better performance, we could avoid a bunch
 // of expensive divides later by also storing the
-// multiplicative inverses of the times.
-pub struct ADSR {
-    /// Attack time in seconds.
-    attack: f32,
-    /// Decay time in seconds.
-    decay: f32,
-    /// Sustain *level*.
-    sustain: f32,
-    /// Release time in seconds.
-    release: f32,
-}
-
-impl ADSR {
-    /// Make a new ADSR envelope. `attack`, `decay` and `release` are
-    /// times in seconds, `sustain` is a level.
-    pub fn new(attack: f32, decay: f32, sustain: f32, release: f32) -> Self {
+// multiplicative inverses of the segment times.
+#[derive(Debug, Clone)]
+pub struct Breakpoints {
+    /// Time in seconds before the first segment.
+    delay: f32,
+    /// Segments run from key down.
+    segments: Vec<Segment>,
+    /// Breakpoint held while the key is down.
+    sustain: Option<usize>,
+    /// Breakpoints bounding the loop run while the key is
+    /// down: on reaching the second, go back to the first.
+    looped: Option<(usize, usize)>,
+    /// Segments run from key up.
+    release: Vec<Segment>,
+}
+
+impl Breakpoints {
+    /// Make a new breakpoint envelope with no delay, sustain
+    /// or loop.
+    pub fn new(segments: Vec<Segment>, release: Vec<Segment>) -> Self {
         Self {
-            attack,
-            decay,
-            sustain,
+            delay: 0.0,
+            segments,
+            sustain: None,
+            looped: None,
             release,
         }
     }
+
+    /// Make an Attack-Decay-Sustain-Release envelope with
+    /// linear segments. Times are in seconds; `sustain` is a
+    /// level.
+    pub fn adsr(attack: f32, decay: f32, sustain: f32, release: f32) -> Self {
+        let lin = Curve::Linear;
+        Self::new(
+            vec![
+                Segment::new(attack, 1.0, lin),
+                Segment::new(decay, sustain, lin),
+            ],
+            vec![Segment::new(release, 0.0, lin)],
+        )
+        .with_sustain(1)
+    }
+
+    /// Make a Delay-Attack-Hold-Decay-Sustain-Release
+    /// envelope with linear segments. Times are in seconds;
+    /// `sustain` is a level.
+    pub fn dahdsr(
+        delay: f32,
+        attack: f32,
+        hold: f32,
+        decay: f32,
+        sustain: f32,
+        release: f32,
+    ) -> Self {
+        let lin = Curve::Linear;
+        Self::new(
+            vec![
+                Segment::new(attack, 1.0, lin),
+                Segment::new(hold, 1.0, lin),
+                Segment::new(decay, sustain, lin),
+            ],
+            vec![Segment::new(release, 0.0, lin)],
+        )
+        .with_delay(delay)
+        .with_sustain(2)
+    }
+
+    /// Set the shapes of the segments: the first (attack)
+    /// segment, the rest of the key-down (decay) segments,
+    /// and the release segments.
+    pub fn with_curves(mut self, attack: Curve, decay: Curve, release: Curve) -> Self {
+        for (i, segment) in self.segments.iter_mut().enumerate() {
+            segment.curve = if i == 0 { attack } else { decay };
+        }
+        for segment in &mut self.release {
+            segment.curve = release;
+        }
+        self
+    }
+
+    /// Set the delay time in seconds.
+    pub fn with_delay(mut self, delay: f32) -> Self {
+        self.delay = delay;
+        self
+    }
+
+    /// Set the sustain breakpoint. Panics if there is no
+    /// such breakpoint.
+    pub fn with_sustain(mut self, breakpoint: usize) -> Self {
+        assert!(breakpoint < self.segments.len(), "no sustain breakpoint");
+        self.sustain = Some(breakpoint);
+        self
+    }
+
+    /// Set the loop region between two breakpoints. Panics
+    /// if the region is empty or takes no time.
+    pub fn with_loop(mut self, start: usize, end: usize) -> Self {
+        assert!(start < end && end < self.segments.len(), "bad loop");
+        let time: f32 = self.segments[start + 1..=end].iter().map(|s| s.time).sum();
+        assert!(time > 0.0, "loop takes no time");
+        self.looped = Some((start, end));
+        self
+    }
+
+    /// Total release time in seconds.
+    pub fn release_time(&self) -> f32 {
+        self.release.iter().map(|s| s.time).sum()
+    }
+}
+
+/// Where an envelope is in its run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Stage {
+    Delay,
+    Segment(usize),
+    Sustain,
+    Release(usize),
+    Done,
 }
 
 pub struct Envelope<'a> {
-    /// Time in seconds since start of envelope.
+    /// Time in seconds since start of current stage.
     t: f32,
-    /// Time in seconds at which note release happened.
-    release_time: Option<f32>,
-    /// Last level output pre-release, treated as sustain
-    /// level during release.
-    sus: f32,
-    /// ADSR envelope parameters.
-    env: &'a ADSR,
+    /// Current stage.
+    stage: Stage,
+    /// Level at the start of the current segment.
+    start: f32,
+    /// Last level output.
+    level: f32,
+    /// Key has been released.
+    released: bool,
+    /// Multiplier for the time of the first segment.
+    attack_scale: f32,
+    /// Time per sample in seconds.
+    dt: f32,
+    /// Envelope parameters.
+    env: &'a Breakpoints,
 }
 
 impl<'a> Envelope<'a> {
-    pub fn new(env: &'a ADSR) -> Self {
+    /// Make a new envelope running at the given sample
+    /// rate.
+    pub fn new(env: &'a Breakpoints, rate: u32) -> Self {
         Self {
             t: 0.0,
-            release_time: None,
-            sus: env.sustain,
+            stage: Stage::Delay,
+            start: 0.0,
+            level: 0.0,
+            released: false,
+            attack_scale: 1.0,
+            dt: 1.0 / rate as f32,
             env,
         }
     }
 
-    /// Enter the release phase.
+    /// Stretch or shrink the attack (first segment) time
+    /// by the given factor.
+    pub fn with_attack_scale(mut self, scale: f32) -> Self {
+        self.attack_scale = scale;
+        self
+    }
+
+    /// Last level output.
+    pub fn level(&self) -> f32 {
+        self.level
+    }
+
+    /// True once the release phase has been entered.
+    pub fn is_released(&self) -> bool {
+        self.released
+    }
+
+    /// Enter the release phase. Does nothing if the
+    /// envelope is already releasing or done.
     pub fn release(&mut self) {
-        self.release_time = Some(self.t);
+        if !matches!(self.stage, Stage::Release(_) | Stage::Done) {
+            self.start_release();
+        }
+    }
+
+    /// Start over from the current level, skipping the
+    /// delay.
+    pub fn retrigger(&mut self) {
+        self.released = false;
+        self.start = self.level;
+        // The delay stage moves on without touching the
+        // level when its time is up.
+        self.t = self.env.delay;
+        self.stage = Stage::Delay;
+    }
+
+    // Start the release segments from the current level.
+    fn start_release(&mut self) {
+        self.released = true;
+        self.t = 0.0;
+        self.start = self.level;
+        self.stage = Stage::Release(0);
+    }
+
+    // Stage following the given key-down segment.
+    fn after_segment(&self, i: usize) -> Stage {
+        let held = !self.released;
+        if held && self.env.sustain == Some(i) {
+            Stage::Sustain
+        } else if held && self.env.looped.map(|(_, end)| end) == Some(i) {
+            Stage::Segment(self.env.looped.unwrap().0 + 1)
+        } else if i + 1 < self.env.segments.len() {
+            Stage::Segment(i + 1)
+        } else {
+            Stage::Release(0)
+        }
     }
 }
 
@@ -64,73 +318,262 @@ impl Iterator for Envelope<'_> {
     //
     // Envelope generation is a pain in the neck, especially
     // given multiple samples. It is possible for the
-    // current segment of envelope to span attack, decay and
-    // sustain, or to span release and continue "off the
-    // end". It is also possible to have "early release", at
-    // which point release happens either from the current
-    // level or the nominal sustain level: we choose the
-    // former.
+    // current sample to land past several short segments,
+    // or past the end of the release. It is also possible
+    // to have "early release", at which point release
+    // happens either from the current level or the nominal
+    // sustain level: we choose the former.
     //
     // The envelope has a built-in timer that starts at 0
-    // samples and auto-increments.
+    // samples at the start of each stage and
+    // auto-increments.
     fn next(&mut self) -> Option<Self::Item> {
-        // Read and bump the timer.
-        let t = self.t;
-        self.t += 1.0 / SAMPLE_RATE as f32;
-
-        // Handle release phase.
-        if let Some(rt) = self.release_time {
-            // Release is special. We a sample on the slope
-            // from the effective sustain (level where the
-            // key was released) to 0.
-
-            // The signal had level sus at time rt.  We are
-            // now at some later time t. If t is off the end
-            // we are done.
-            let rl = self.env.release;
-            if t >= rt + rl {
-                return None;
+        // Step through finished stages until the timer lands
+        // in one.
+        loop {
+            match self.stage {
+                Stage::Delay => {
+                    if self.t < self.env.delay {
+                        break;
+                    }
+                    self.t -= self.env.delay;
+                    if self.env.segments.is_empty() {
+                        self.start_release();
+                    } else {
+                        self.stage = Stage::Segment(0);
+                    }
+                }
+                Stage::Segment(i) | Stage::Release(i) => {
+                    let segments = if let Stage::Segment(_) = self.stage {
+                        &self.env.segments
+                    } else {
+                        &self.env.release
+                    };
+                    let Some(seg) = segments.get(i) else {
+                        self.stage = Stage::Done;
+                        continue;
+                    };
+                    let time = if self.stage == Stage::Segment(0) {
+                        seg.time * self.attack_scale
+                    } else {
+                        seg.time
+                    };
+                    if self.t < time {
+                        let g = seg.curve.shape(self.t / time);
+                        self.level = self.start + (seg.level - self.start) * g;
+                        break;
+                    }
+                    // Segment finished: move on from its
+                    // target level.
+                    self.t -= time;
+                    self.start = seg.level;
+                    self.level = seg.level;
+                    self.stage = match self.stage {
+                        Stage::Segment(i) => self.after_segment(i),
+                        _ => Stage::Release(i + 1),
+                    };
+                    if self.stage == Stage::Release(0) {
+                        self.start_release();
+                    }
+                }
+                Stage::Sustain => {
+                    self.t = 0.0;
+                    break;
+                }
+                Stage::Done => return None,
             }
-            // Turns out t is in range. Compute and return the envelope
-            return Some(self.sus * (1.0 - (t - rt) / rl));
         }
 
-        // Try phases in reverse order until we find one that applies.
-        let ta = self.env.attack;
-        let td = self.env.decay;
-        let sus = self.env.sustain;
-        let e = if t >= ta + td {
-            // Sustain phase.
-            sus
-        } else if t >= ta {
-            // Decay phase.
-            1.0 + (sus - 1.0) * (t - ta) / td
-        } else {
-            // Attack phase.
-            t / ta
-        };
+        // Bump the timer.
+        self.t += self.dt;
+        Some(self.level)
+    }
+}
+
+/// Decibel range of `VelocityCurve::Exponential`.
+const VELOCITY_DB: f32 = 40.0;
+
+/// Lowpass cutoff in Hz of a full-velocity note with
+/// velocity-tracking filter.
+const VELOCITY_CUTOFF: f32 = 20_000.0;
+
+/// Mapping from key velocity to note amplitude.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VelocityCurve {
+    /// Ignore velocity: every note is full amplitude.
+    Fixed,
+    /// Amplitude proportional to velocity.
+    Linear,
+    /// Square root: quiet playing comes out louder.
+    Soft,
+    /// Square: takes a harder touch to get loud.
+    Hard,
+    /// Velocity is linear in decibels.
+    Exponential,
+}
 
-        // Make sure to remember the returned level in case we are
-        // about to get a release.
-        self.sus = e;
-        Some(e)
+impl VelocityCurve {
+    /// Amplitude for velocity `v` in `0.0..=1.0`.
+    pub fn amplitude(self, v: f32) -> f32 {
+        match self {
+            VelocityCurve::Fixed => 1.0,
+            VelocityCurve::Linear => v,
+            VelocityCurve::Soft => v.sqrt(),
+            VelocityCurve::Hard => v * v,
+            VelocityCurve::Exponential => {
+                if v == 0.0 {
+                    0.0
+                } else {
+                    f32::powf(10.0, VELOCITY_DB * (v - 1.0) / 20.0)
+                }
+            }
+        }
+    }
+}
+
+impl FromStr for VelocityCurve {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "fixed" | "none" => Ok(VelocityCurve::Fixed),
+            "lin" | "linear" => Ok(VelocityCurve::Linear),
+            "soft" => Ok(VelocityCurve::Soft),
+            "hard" => Ok(VelocityCurve::Hard),
+            "exp" | "exponential" => Ok(VelocityCurve::Exponential),
+            _ => Err(format!(
+                "invalid velocity curve {}: use fixed, lin, soft, hard or exp",
+                s
+            )),
+        }
+    }
+}
+
+/// How notes respond to key velocity.
+#[derive(Debug, Clone, Copy)]
+pub struct Velocity {
+    /// Amplitude curve.
+    pub curve: VelocityCurve,
+    /// Attack time stretch: a zero-velocity note has its
+    /// attack time multiplied by `1 + attack`. Zero turns
+    /// this off.
+    pub attack: f32,
+    /// Lowpass cutoff tracking in octaves: a zero-velocity
+    /// note has its cutoff lowered this many octaves from
+    /// 20 kHz. Zero turns the filter off.
+    pub cutoff: f32,
+}
+
+impl Default for Velocity {
+    fn default() -> Self {
+        Self {
+            curve: VelocityCurve::Linear,
+            attack: 0.0,
+            cutoff: 0.0,
+        }
+    }
+}
+
+/// One-pole lowpass filter.
+struct Lowpass {
+    /// Smoothing coefficient.
+    a: f32,
+    /// Last output.
+    y: f32,
+}
+
+impl Lowpass {
+    fn new(cutoff: f32, rate: u32) -> Self {
+        let a = 1.0 - f32::exp(-std::f32::consts::TAU * cutoff / rate as f32);
+        Self { a, y: 0.0 }
+    }
+
+    fn filter(&mut self, x: f32) -> f32 {
+        self.y += self.a * (x - self.y);
+        self.y
     }
 }
 
 pub struct Note<'a> {
     signal: Box<Signal<'a>>,
     envelope: Envelope<'a>,
+    /// Velocity amplitude.
+    amp: f32,
+    /// Velocity lowpass.
+    lowpass: Option<Lowpass>,
+    /// Gain and per-sample gain step of a fade-out.
+    fade: Option<(f32, f32)>,
+    /// Sample rate.
+    rate: u32,
 }
 
 impl<'a> Note<'a> {
-    pub fn new(voice: &'a dyn Voice<'a>, adsr: &'a ADSR, freq: f32) -> Self {
-        let signal = voice.iter_freq(freq);
-        let envelope = Envelope::new(adsr);
-        Self { signal, envelope }
+    /// Start a note with the given MIDI key velocity at the
+    /// given sample rate.
+    pub fn new(
+        voice: &'a dyn Voice<'a>,
+        env: &'a Breakpoints,
+        freq: f32,
+        velocity: u8,
+        response: &Velocity,
+        rate: u32,
+    ) -> Self {
+        let v = f32::from(velocity.min(127)) / 127.0;
+        let signal = voice.iter_note(freq, velocity.min(127), rate);
+        let envelope =
+            Envelope::new(env, rate).with_attack_scale(1.0 + response.attack * (1.0 - v));
+        let amp = response.curve.amplitude(v);
+        let lowpass = if response.cutoff > 0.0 {
+            let cutoff = VELOCITY_CUTOFF * f32::powf(2.0, -response.cutoff * (1.0 - v));
+            Some(Lowpass::new(cutoff, rate))
+        } else {
+            None
+        };
+        Self {
+            signal,
+            envelope,
+            amp,
+            lowpass,
+            fade: None,
+            rate,
+        }
     }
 
     pub fn release(&mut self) {
         self.envelope.release();
+        self.signal.release();
+    }
+}
+
+impl MixerNote for Note<'_> {
+    fn release(&mut self) {
+        Note::release(self);
+    }
+
+    fn retrigger(&mut self, note: Self) {
+        // Take everything from the new strike, but carry on
+        // the envelope from the current loudness so as not
+        // to jump.
+        let loudness = self.envelope.level * self.amp;
+        *self = note;
+        if self.amp > 0.0 {
+            self.envelope.level = loudness / self.amp;
+        }
+        self.envelope.retrigger();
+    }
+
+    fn level(&self) -> f32 {
+        let fade = self.fade.map(|(g, _)| g).unwrap_or(1.0);
+        self.envelope.level() * self.amp * fade
+    }
+
+    fn is_released(&self) -> bool {
+        self.envelope.is_released()
+    }
+
+    fn fade_out(&mut self, time: f32) {
+        let step = 1.0 / f32::max(1.0, time * self.rate as f32);
+        self.fade = Some((1.0, step));
     }
 }
 
@@ -140,6 +583,223 @@ impl<'a> Iterator for Note<'a> {
     fn next(&mut self) -> Option<Self::Item> {
         let e = self.envelope.next()?;
         let s = self.signal.next()?;
-        Some(e * s)
+        let mut s = e * s * self.amp;
+        if let Some((ref mut g, step)) = self.fade {
+            if *g <= 0.0 {
+                return None;
+            }
+            s *= *g;
+            *g -= step;
+        }
+        match self.lowpass {
+            Some(ref mut lowpass) => Some(lowpass.filter(s)),
+            None => Some(s),
+        }
     }
 }
+
+#[test]
+// Check that curves run from start to target, and bend the
+// right way.
+fn test_curve_shape() {
+    let curves = [
+        Curve::Linear,
+        Curve::Exponential,
+        Curve::Logarithmic,
+        Curve::Curvature(2.0),
+        Curve::Curvature(0.0),
+    ];
+    for c in curves {
+        assert!(c.shape(0.0).abs() < 1.0e-6, "{:?}", c);
+        assert!((c.shape(1.0) - 1.0).abs() < 1.0e-6, "{:?}", c);
+    }
+    assert!(Curve::Exponential.shape(0.5) > 0.9);
+    assert!(Curve::Logarithmic.shape(0.5) < 0.1);
+    assert_eq!(Ok(Curve::Curvature(-1.5)), "-1.5".parse());
+}
+
+#[test]
+// Check that an early release with a curved envelope starts
+// from the current level and heads for zero.
+fn test_curved_release() {
+    let curve = Curve::Exponential;
+    let env = Breakpoints::adsr(0.1, 0.1, 0.5, 0.1).with_curves(curve, curve, curve);
+    let mut env = Envelope::new(&env, DEFAULT_SAMPLE_RATE);
+    let level = env
+        .by_ref()
+        .take(DEFAULT_SAMPLE_RATE as usize / 20)
+        .last()
+        .unwrap();
+    assert!(level > 0.9);
+    env.release();
+    let release: Vec<f32> = env.collect();
+    assert!((release[0] - level).abs() < 1.0e-6);
+    assert!(release.windows(2).all(|w| w[1] < w[0]));
+    assert!(*release.last().unwrap() < 0.01);
+}
+
+#[test]
+// Check that running out of segments counts as a release,
+// so that a later key up neither stretches the release nor
+// revives a finished envelope.
+fn test_natural_release() {
+    let lin = Curve::Linear;
+    let env = Breakpoints::new(
+        vec![Segment::new(0.01, 1.0, lin)],
+        vec![Segment::new(0.01, 0.0, lin)],
+    );
+    let run = |late_release| {
+        let mut env = Envelope::new(&env, DEFAULT_SAMPLE_RATE);
+        let n = env.by_ref().take(720).count();
+        assert!(env.is_released());
+        if late_release {
+            env.release();
+        }
+        n + env.by_ref().count()
+    };
+    assert_eq!(run(false), run(true));
+
+    let mut env = Envelope::new(&env, DEFAULT_SAMPLE_RATE);
+    assert!(env.by_ref().count() < 1000);
+    env.release();
+    assert_eq!(None, env.next());
+}
+
+#[test]
+// Check that a retrigger during release climbs back from
+// the current level rather than restarting from zero.
+fn test_retrigger() {
+    let env = Breakpoints::adsr(0.1, 0.1, 0.5, 0.1);
+    let mut env = Envelope::new(&env, DEFAULT_SAMPLE_RATE);
+    env.by_ref().take(DEFAULT_SAMPLE_RATE as usize / 2).count();
+    env.release();
+    let level = env
+        .by_ref()
+        .take(DEFAULT_SAMPLE_RATE as usize / 40)
+        .last()
+        .unwrap();
+    assert!(level > 0.1 && level < 0.5);
+    env.retrigger();
+    assert!(!env.is_released());
+    let attack: Vec<f32> = env
+        .by_ref()
+        .take(DEFAULT_SAMPLE_RATE as usize / 20)
+        .collect();
+    assert!((attack[0] - level).abs() < 0.01);
+    assert!(attack.windows(2).all(|w| w[1] > w[0]));
+}
+
+#[test]
+// Check that the ADSR preset matches the classic
+// piecewise-linear envelope.
+fn test_adsr_preset() {
+    let (ta, td, sus, tr) = (0.01, 0.02, 0.5, 0.03);
+    let env = Breakpoints::adsr(ta, td, sus, tr);
+    let mut env = Envelope::new(&env, DEFAULT_SAMPLE_RATE);
+    let dt = 1.0 / DEFAULT_SAMPLE_RATE as f32;
+    let n = DEFAULT_SAMPLE_RATE as usize / 10;
+    for (i, e) in env.by_ref().take(n).enumerate() {
+        let t = i as f32 * dt;
+        let want = if t >= ta + td {
+            sus
+        } else if t >= ta {
+            1.0 + (sus - 1.0) * (t - ta) / td
+        } else {
+            t / ta
+        };
+        assert!((e - want).abs() < 1.0e-3, "{} at {}", e, t);
+    }
+    env.release();
+    let release: Vec<f32> = env.collect();
+    assert!((release.len() as f32 * dt - tr).abs() < 2.0 * dt);
+    for (i, e) in release.into_iter().enumerate() {
+        let want = sus * (1.0 - i as f32 * dt / tr);
+        assert!((e - want).abs() < 1.0e-3);
+    }
+}
+
+#[test]
+// Check delay and hold stages, and that an envelope with no
+// sustain releases by itself.
+fn test_dahdsr() {
+    let env = Breakpoints::dahdsr(0.01, 0.01, 0.01, 0.01, 0.0, 0.01);
+    let ms = DEFAULT_SAMPLE_RATE as usize / 1000;
+    let levels: Vec<f32> = Envelope::new(&env, DEFAULT_SAMPLE_RATE)
+        .take(50 * ms)
+        .collect();
+    assert!(levels[..10 * ms].iter().all(|&e| e == 0.0));
+    assert!(levels[20 * ms + 1..30 * ms].iter().all(|&e| e == 1.0));
+    assert!(levels[40 * ms + 1..].iter().all(|&e| e == 0.0));
+
+    let lin = Curve::Linear;
+    let env = Breakpoints::new(vec![Segment::new(0.01, 1.0, lin)], vec![]);
+    assert_eq!(10 * ms, Envelope::new(&env, DEFAULT_SAMPLE_RATE).count());
+
+    // Times are in seconds whatever the sample rate.
+    for rate in [44_100, 96_000] {
+        let n = Envelope::new(&env, rate).count() as i64;
+        assert!((n - rate as i64 / 100).abs() <= 1, "{} {}", rate, n);
+    }
+}
+
+#[test]
+// Check that a loop repeats until release.
+fn test_breakpoint_loop() {
+    let lin = Curve::Linear;
+    let env = Breakpoints::new(
+        vec![
+            Segment::new(0.01, 1.0, lin),
+            Segment::new(0.01, 0.0, lin),
+            Segment::new(0.01, 1.0, lin),
+        ],
+        vec![Segment::new(0.01, 0.0, lin)],
+    )
+    .with_loop(0, 2);
+    let ms = DEFAULT_SAMPLE_RATE as usize / 1000;
+    let mut env = Envelope::new(&env, DEFAULT_SAMPLE_RATE);
+    let levels: Vec<f32> = env.by_ref().take(100 * ms).collect();
+    // Peaks at 10ms, 30ms, 50ms, ...
+    for peak in (10..90).step_by(20) {
+        assert!(levels[peak * ms] > 0.99, "no peak at {}ms", peak);
+        assert!(levels[(peak + 10) * ms] < 0.01);
+    }
+    env.release();
+    assert!(env.count().abs_diff(10 * ms) <= 1);
+}
+
+#[test]
+// Check the velocity curves at their ends and middle.
+fn test_velocity_curves() {
+    use VelocityCurve::*;
+    for c in [Linear, Soft, Hard, Exponential] {
+        assert_eq!(0.0, c.amplitude(0.0), "{:?}", c);
+        assert!((c.amplitude(1.0) - 1.0).abs() < 1.0e-6, "{:?}", c);
+    }
+    assert_eq!(1.0, Fixed.amplitude(0.0));
+    assert!(Soft.amplitude(0.5) > Linear.amplitude(0.5));
+    assert!(Hard.amplitude(0.5) < Linear.amplitude(0.5));
+    assert!((Exponential.amplitude(0.5) - 0.1).abs() < 1.0e-6);
+}
+
+#[test]
+// Check that velocity scales note amplitude, and stretches
+// attack when asked to.
+fn test_note_velocity() {
+    let voice = WaveGen::new(WaveShape::Square);
+    let env = Breakpoints::adsr(0.01, 0.0, 1.0, 0.01);
+    let response = Velocity {
+        attack: 1.0,
+        ..Default::default()
+    };
+    let peak = |vel| {
+        let note = Note::new(&voice, &env, 100.0, vel, &response, DEFAULT_SAMPLE_RATE);
+        let samples: Vec<f32> = note.take(DEFAULT_SAMPLE_RATE as usize / 10).collect();
+        let peak = samples.iter().fold(0.0f32, |m, s| m.max(s.abs()));
+        let rise = samples.iter().position(|s| s.abs() >= 0.99 * peak).unwrap();
+        (peak, rise)
+    };
+    let (loud, loud_rise) = peak(127);
+    let (quiet, quiet_rise) = peak(64);
+    assert!((quiet / loud - 64.0 / 127.0).abs() < 0.01);
+    assert!(quiet_rise > loud_rise + DEFAULT_SAMPLE_RATE as usize / 400);
+}
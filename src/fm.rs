@@ -0,0 +1,387 @@
+// Copyright © 2022 Bart Massey
+// [This program is licensed under the "MIT License"]
+// Please see the file LICENSE in the source
+// distribution of this software for license terms.
+
+//! Multi-operator FM synthesis.
+//!
+//! Each operator is a sine oscillator with its own envelope,
+//! output level and self-feedback. An algorithm routes
+//! operators into each other's phase, and picks which
+//! operators are heard. This is the classic four-operator
+//! arrangement of the Yamaha DX21 / TX81Z family.
+
+use std::f32::consts::TAU;
+
+use crate::*;
+
+/// Number of operators in an FM voice.
+pub const NOPS: usize = 4;
+
+/// How an operator's frequency is determined.
+#[derive(Debug, Clone, Copy)]
+pub enum OpFreq {
+    /// Multiple of the note frequency.
+    Ratio(f32),
+    /// Fixed frequency in Hz, regardless of note.
+    Fixed(f32),
+}
+
+/// FM operator parameters.
+pub struct Operator {
+    /// Operator frequency.
+    pub freq: OpFreq,
+    /// Output level. For carriers this is an amplitude; for
+    /// modulators it is the modulation index in radians.
+    pub level: f32,
+    /// Self-modulation index in radians.
+    pub feedback: f32,
+    /// Operator envelope.
+    pub env: Breakpoints,
+}
+
+impl Operator {
+    /// Make a new operator at the given frequency ratio with
+    /// no feedback.
+    pub fn new(ratio: f32, level: f32, env: Breakpoints) -> Self {
+        Self {
+            freq: OpFreq::Ratio(ratio),
+            level,
+            feedback: 0.0,
+            env,
+        }
+    }
+
+    /// Set the operator to a fixed frequency in Hz.
+    pub fn fixed(mut self, freq: f32) -> Self {
+        self.freq = OpFreq::Fixed(freq);
+        self
+    }
+
+    /// Set the operator self-feedback.
+    pub fn feedback(mut self, feedback: f32) -> Self {
+        self.feedback = feedback;
+        self
+    }
+}
+
+/// Operator routing. Operator `i` may only be modulated by
+/// operators numbered higher than `i`, so that operators
+/// can be computed from last to first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Algorithm {
+    /// Bitmask of the modulators of each operator.
+    pub modulators: [u8; NOPS],
+    /// Bitmask of the operators that are heard.
+    pub carriers: u8,
+}
+
+impl Algorithm {
+    /// Check that the routing can be computed: each
+    /// operator is modulated only by higher-numbered
+    /// operators, and something is heard.
+    pub fn is_valid(&self) -> bool {
+        let all = (1 << NOPS) - 1;
+        self.carriers != 0
+            && self.carriers & !all == 0
+            && self
+                .modulators
+                .iter()
+                .enumerate()
+                .all(|(i, &m)| m & !all == 0 && m & ((2 << i) - 1) == 0)
+    }
+}
+
+/// Names of the built-in patches, for [FmGen::patch].
+pub const PATCHES: [&str; 5] = ["epiano", "bell", "bass", "brass", "organ"];
+
+/// The eight TX81Z algorithms, with operator 1 numbered 0.
+pub const ALGORITHMS: [Algorithm; 8] = [
+    // 4 → 3 → 2 → 1
+    Algorithm {
+        modulators: [0b0010, 0b0100, 0b1000, 0],
+        carriers: 0b0001,
+    },
+    // (3 + 4) → 2 → 1
+    Algorithm {
+        modulators: [0b0010, 0b1100, 0, 0],
+        carriers: 0b0001,
+    },
+    // (4 + (3 → 2)) → 1
+    Algorithm {
+        modulators: [0b1010, 0b0100, 0, 0],
+        carriers: 0b0001,
+    },
+    // (2 + (4 → 3)) → 1
+    Algorithm {
+        modulators: [0b0110, 0, 0b1000, 0],
+        carriers: 0b0001,
+    },
+    // 2 → 1, 4 → 3
+    Algorithm {
+        modulators: [0b0010, 0, 0b1000, 0],
+        carriers: 0b0101,
+    },
+    // 4 → 1, 4 → 2, 4 → 3
+    Algorithm {
+        modulators: [0b1000, 0b1000, 0b1000, 0],
+        carriers: 0b0111,
+    },
+    // 4 → 3, with 1 and 2 alone
+    Algorithm {
+        modulators: [0, 0, 0b1000, 0],
+        carriers: 0b0111,
+    },
+    // Additive: all carriers
+    Algorithm {
+        modulators: [0, 0, 0, 0],
+        carriers: 0b1111,
+    },
+];
+
+/// FM voice: a set of operators and an algorithm.
+pub struct FmGen {
+    ops: [Operator; NOPS],
+    algorithm: Algorithm,
+}
+
+impl FmGen {
+    /// Make a new FM voice. Panics if the algorithm is not
+    /// valid.
+    pub fn new(ops: [Operator; NOPS], algorithm: Algorithm) -> Self {
+        assert!(algorithm.is_valid(), "invalid FM algorithm");
+        Self { ops, algorithm }
+    }
+
+    /// Get a built-in patch by name.
+    pub fn patch(name: &str) -> Option<Self> {
+        let (ops, alg) = match name {
+            "epiano" => (
+                [
+                    Operator::new(1.0, 1.0, Breakpoints::adsr(0.002, 1.5, 0.2, 0.4)),
+                    Operator::new(14.0, 0.6, Breakpoints::adsr(0.001, 0.3, 0.0, 0.2)),
+                    Operator::new(1.0, 1.0, Breakpoints::adsr(0.002, 2.0, 0.3, 0.4)),
+                    Operator::new(1.0, 1.8, Breakpoints::adsr(0.001, 1.0, 0.2, 0.3)),
+                ],
+                4,
+            ),
+            "bell" => (
+                [
+                    Operator::new(1.0, 1.0, Breakpoints::adsr(0.001, 4.0, 0.0, 2.0)),
+                    Operator::new(3.5, 3.0, Breakpoints::adsr(0.001, 3.0, 0.0, 2.0)),
+                    Operator::new(1.0, 0.5, Breakpoints::adsr(0.001, 2.0, 0.0, 1.5)),
+                    Operator::new(1.41, 2.0, Breakpoints::adsr(0.001, 1.0, 0.0, 1.0)),
+                ],
+                4,
+            ),
+            "bass" => (
+                [
+                    Operator::new(1.0, 1.0, Breakpoints::adsr(0.002, 0.5, 0.7, 0.1)),
+                    Operator::new(1.0, 2.5, Breakpoints::adsr(0.002, 0.2, 0.3, 0.1)),
+                    Operator::new(0.5, 1.0, Breakpoints::adsr(0.002, 0.3, 0.5, 0.1)),
+                    Operator::new(1.0, 1.0, Breakpoints::adsr(0.002, 0.1, 0.2, 0.1)).feedback(1.2),
+                ],
+                0,
+            ),
+            "brass" => (
+                [
+                    Operator::new(1.0, 1.0, Breakpoints::adsr(0.06, 0.2, 0.8, 0.15)),
+                    Operator::new(1.0, 1.5, Breakpoints::adsr(0.08, 0.3, 0.6, 0.15)),
+                    Operator::new(1.0, 1.0, Breakpoints::adsr(0.05, 0.2, 0.8, 0.15)),
+                    Operator::new(1.0, 1.5, Breakpoints::adsr(0.08, 0.3, 0.6, 0.15)).feedback(0.8),
+                ],
+                4,
+            ),
+            "organ" => (
+                [
+                    Operator::new(0.5, 1.0, Breakpoints::adsr(0.005, 0.0, 1.0, 0.05)),
+                    Operator::new(1.0, 0.8, Breakpoints::adsr(0.005, 0.0, 1.0, 0.05)),
+                    Operator::new(2.0, 0.6, Breakpoints::adsr(0.005, 0.0, 1.0, 0.05)),
+                    Operator::new(3.0, 0.4, Breakpoints::adsr(0.005, 0.0, 1.0, 0.05)),
+                ],
+                7,
+            ),
+            _ => return None,
+        };
+        Some(Self::new(ops, ALGORITHMS[alg]))
+    }
+
+    /// Longest operator release time in seconds. A note's
+    /// own envelope should release no faster than this, or
+    /// the operator release will be cut off.
+    pub fn release_time(&self) -> f32 {
+        self.ops
+            .iter()
+            .map(|op| op.env.release_time())
+            .fold(0.0, f32::max)
+    }
+}
+
+/// Running state of an FM operator.
+struct OpState<'a> {
+    /// Phase in cycles.
+    phase: f32,
+    /// Phase increment per sample, in cycles.
+    dphase: f32,
+    /// Last two outputs, for feedback.
+    prev: [f32; 2],
+    /// Operator envelope; `None` once finished.
+    envelope: Option<Envelope<'a>>,
+    /// Operator parameters.
+    op: &'a Operator,
+}
+
+/// A sounding FM note.
+struct FmNote<'a> {
+    ops: Vec<OpState<'a>>,
+    algorithm: Algorithm,
+    /// Sample rate.
+    rate: u32,
+}
+
+impl<'a> FmNote<'a> {
+    fn new(gen: &'a FmGen, freq: f32, rate: u32) -> Self {
+        let ops = gen
+            .ops
+            .iter()
+            .map(|op| {
+                let f = match op.freq {
+                    OpFreq::Ratio(r) => r * freq,
+                    OpFreq::Fixed(f) => f,
+                };
+                OpState {
+                    phase: 0.0,
+                    dphase: f / rate as f32,
+                    prev: [0.0; 2],
+                    envelope: Some(Envelope::new(&op.env, rate)),
+                    op,
+                }
+            })
+            .collect();
+        Self {
+            ops,
+            algorithm: gen.algorithm,
+            rate,
+        }
+    }
+}
+
+impl Iterator for FmNote<'_> {
+    type Item = f32;
+
+    // Compute operators from last to first, so that each
+    // operator's modulators are ready before it is.
+    fn next(&mut self) -> Option<f32> {
+        let mut out = [0.0; NOPS];
+        for i in (0..NOPS).rev() {
+            let mods = self.algorithm.modulators[i];
+            let mut m: f32 = (i + 1..NOPS)
+                .filter(|j| mods & (1 << j) != 0)
+                .map(|j| out[j])
+                .sum();
+            let st = &mut self.ops[i];
+            m += 0.5 * st.op.feedback * (st.prev[0] + st.prev[1]);
+            let e = st.envelope.as_mut().and_then(|e| e.next());
+            if e.is_none() {
+                st.envelope = None;
+            }
+            let s = e.unwrap_or(0.0) * st.op.level * f32::sin(TAU * st.phase + m);
+            st.prev = [s, st.prev[0]];
+            st.phase += st.dphase;
+            st.phase -= st.phase.floor();
+            out[i] = s;
+        }
+
+        // The note is done when all of its carriers are.
+        let carriers = self.algorithm.carriers;
+        let heard = (0..NOPS).filter(|i| carriers & (1 << i) != 0);
+        if heard.clone().all(|i| self.ops[i].envelope.is_none()) {
+            return None;
+        }
+        let ncarriers = heard.clone().count() as f32;
+        Some(heard.map(|i| out[i]).sum::<f32>() / ncarriers)
+    }
+}
+
+impl Stream for FmNote<'_> {
+    fn release(&mut self) {
+        for st in &mut self.ops {
+            if let Some(e) = st.envelope.as_mut() {
+                e.release();
+            }
+        }
+    }
+
+    fn retrigger(&mut self) {
+        let rate = self.rate;
+        for st in &mut self.ops {
+            let envelope = match st.envelope.take() {
+                Some(mut e) => {
+                    e.retrigger();
+                    e
+                }
+                None => Envelope::new(&st.op.env, rate),
+            };
+            st.envelope = Some(envelope);
+        }
+    }
+}
+
+impl<'a> Voice<'a> for FmGen {
+    fn iter_freq(&'a self, freq: f32, rate: u32) -> Box<Signal<'a>> {
+        Box::new(FmNote::new(self, freq, rate))
+    }
+}
+
+#[test]
+// Check that the built-in algorithms can be computed.
+fn test_algorithms_valid() {
+    assert!(ALGORITHMS.iter().all(Algorithm::is_valid));
+    let bad = Algorithm {
+        modulators: [0, 0b0001, 0, 0],
+        carriers: 0b0001,
+    };
+    assert!(!bad.is_valid());
+}
+
+#[test]
+// Check that a lone unmodulated carrier is a sine wave.
+fn test_fm_sine() {
+    let adsr = || Breakpoints::adsr(0.0, 0.0, 1.0, 0.1);
+    let ops = [
+        Operator::new(1.0, 1.0, adsr()),
+        Operator::new(1.0, 0.0, adsr()),
+        Operator::new(1.0, 0.0, adsr()),
+        Operator::new(1.0, 0.0, adsr()),
+    ];
+    let gen = FmGen::new(ops, ALGORITHMS[0]);
+    let freq = 440.0;
+    for (i, s) in gen
+        .iter_freq(freq, DEFAULT_SAMPLE_RATE)
+        .take(1000)
+        .enumerate()
+    {
+        let t = TAU * freq * i as f32 / DEFAULT_SAMPLE_RATE as f32;
+        assert!((s - t.sin()).abs() < 1.0e-3);
+    }
+}
+
+#[test]
+// Check that every listed patch exists.
+fn test_patches() {
+    for name in PATCHES {
+        assert!(FmGen::patch(name).is_some(), "{}", name);
+    }
+    assert!(FmGen::patch("kazoo").is_none());
+}
+
+#[test]
+// Check that a released FM note finishes.
+fn test_fm_release() {
+    let gen = FmGen::patch("organ").unwrap();
+    let mut note = gen.iter_freq(440.0, DEFAULT_SAMPLE_RATE);
+    assert!(note.by_ref().take(1000).all(|s| s.abs() <= 1.0));
+    note.release();
+    let n = (gen.release_time() * DEFAULT_SAMPLE_RATE as f32) as usize;
+    assert!(note.count().abs_diff(n) <= 1);
+}
@@ -0,0 +1,355 @@
+// Copyright © 2022 Bart Massey
+// [This program is licensed under the "MIT License"]
+// Please see the file LICENSE in the source
+// distribution of this software for license terms.
+
+//! Multi-sample instruments.
+//!
+//! A single sample sounds unnatural when stretched far from
+//! the pitch it was recorded at. An instrument instead holds
+//! several samples, each mapped to a zone of keys and
+//! velocities, and plays each note from the best zone. A
+//! zone may hold alternate takes of the same note, which are
+//! played in turn. A zone may also have its own amplitude
+//! envelope, applied on top of the note's.
+
+use std::ops::RangeInclusive;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::*;
+
+// MIDI key, possibly fractional, of a frequency.
+fn key_of(freq: f32) -> f32 {
+    69.0 + 12.0 * f32::log2(freq / 440.0)
+}
+
+/// Samples mapped onto ranges of keys and velocities.
+#[derive(Debug)]
+pub struct Zone {
+    /// Keys played from this zone.
+    pub keys: RangeInclusive<u8>,
+    /// Velocities played from this zone.
+    pub velocities: RangeInclusive<u8>,
+    /// Alternate samples, played round-robin.
+    loops: Vec<Loop>,
+    /// Next alternate to play.
+    next: AtomicUsize,
+    /// Amplitude envelope for notes from this zone.
+    env: Option<Breakpoints>,
+}
+
+impl Zone {
+    /// Make a zone with the given alternate samples. There
+    /// must be at least one.
+    pub fn new(keys: RangeInclusive<u8>, velocities: RangeInclusive<u8>, loops: Vec<Loop>) -> Self {
+        assert!(!loops.is_empty(), "zone with no samples");
+        Self {
+            keys,
+            velocities,
+            loops,
+            next: AtomicUsize::new(0),
+            env: None,
+        }
+    }
+
+    /// Give notes from this zone their own envelope.
+    pub fn with_envelope(mut self, env: Breakpoints) -> Self {
+        self.env = Some(env);
+        self
+    }
+
+    /// Add another alternate sample.
+    pub fn push(&mut self, sloop: Loop) {
+        self.loops.push(sloop);
+    }
+
+    // Distance in semitones from the zone's root to the
+    // given key. Unpitched samples are infinitely far away.
+    fn distance(&self, key: f32) -> f32 {
+        match self.loops[0].freq() {
+            Some(f) => (key_of(f) - key).abs(),
+            None => f32::INFINITY,
+        }
+    }
+
+    // Take the next alternate.
+    fn take(&self) -> &Loop {
+        let i = self.next.fetch_add(1, Ordering::Relaxed);
+        &self.loops[i % self.loops.len()]
+    }
+}
+
+/// A sampler voice made of zones.
+#[derive(Debug)]
+pub struct Instrument {
+    zones: Vec<Zone>,
+}
+
+impl Instrument {
+    /// Make an instrument from its zones. There must be at
+    /// least one.
+    pub fn new(zones: Vec<Zone>) -> Self {
+        assert!(!zones.is_empty(), "instrument with no zones");
+        Self { zones }
+    }
+
+    /// Make an instrument from loops and the metadata they
+    /// were made with. Key and velocity ranges come from the
+    /// metadata where given. Otherwise a sample covers the
+    /// keys nearer its root key than any other root key, and
+    /// all velocities. Samples with the same ranges, including
+    /// roots rounding to the same key, become alternates in
+    /// one zone.
+    pub fn from_samples(samples: Vec<(Loop, SampleMeta)>) -> Self {
+        // Split the keyboard halfway between root keys. Roots
+        // that round to the same key share its zone.
+        let key_near = |freq: f32| key_of(freq).round().clamp(0.0, 127.0) as u8;
+        let mut roots: Vec<u8> = samples
+            .iter()
+            .filter(|(_, meta)| meta.keys.is_none())
+            .filter_map(|(sloop, _)| sloop.freq().map(key_near))
+            .collect();
+        roots.sort_unstable();
+        roots.dedup();
+        let keys_around = |root: u8| {
+            let i = roots.binary_search(&root).unwrap();
+            let lo = match i {
+                0 => 0,
+                _ => (roots[i - 1] + root) / 2 + 1,
+            };
+            let hi = match roots.get(i + 1) {
+                Some(&next) => (root + next) / 2,
+                None => 127,
+            };
+            lo..=hi
+        };
+
+        let mut zones: Vec<Zone> = Vec::new();
+        for (sloop, meta) in samples {
+            let keys = match (&meta.keys, sloop.freq()) {
+                (Some(keys), _) => keys.clone(),
+                (None, Some(f)) => keys_around(key_near(f)),
+                (None, None) => 0..=127,
+            };
+            let velocities = meta.velocities.clone().unwrap_or(0..=127);
+            match zones
+                .iter_mut()
+                .find(|z| z.keys == keys && z.velocities == velocities)
+            {
+                Some(zone) => zone.push(sloop),
+                None => zones.push(Zone::new(keys, velocities, vec![sloop])),
+            }
+        }
+        Self::new(zones)
+    }
+
+    /// Interpolate between samples of every zone with the
+    /// given quality.
+    pub fn with_quality(mut self, quality: Quality) -> Self {
+        for zone in &mut self.zones {
+            let loops = std::mem::take(&mut zone.loops);
+            zone.loops = loops.into_iter().map(|l| l.with_quality(quality)).collect();
+        }
+        self
+    }
+
+    /// Zones of the instrument.
+    pub fn zones(&self) -> &[Zone] {
+        &self.zones
+    }
+
+    /// Longest release tail or zone envelope release in
+    /// seconds.
+    pub fn release_time(&self) -> f32 {
+        let tails = self
+            .zones
+            .iter()
+            .flat_map(|z| z.loops.iter())
+            .map(Loop::release_time);
+        let envs = self
+            .zones
+            .iter()
+            .filter_map(|z| z.env.as_ref())
+            .map(Breakpoints::release_time);
+        tails.chain(envs).fold(0.0, f32::max)
+    }
+
+    // Pick the sample for a note. Zones covering the
+    // velocity are preferred, then zones covering the key,
+    // then zones with the nearest root.
+    fn choose(&self, freq: f32, velocity: u8) -> (&Zone, &Loop) {
+        let key = key_of(freq);
+        let nearest = (key.round().clamp(0.0, 127.0)) as u8;
+        let by_velocity: Vec<&Zone> = self
+            .zones
+            .iter()
+            .filter(|z| z.velocities.contains(&velocity))
+            .collect();
+        let candidates = if by_velocity.is_empty() {
+            self.zones.iter().collect()
+        } else {
+            by_velocity
+        };
+        candidates
+            .into_iter()
+            .min_by(|a, b| {
+                let miss = |z: &Zone| !z.keys.contains(&nearest);
+                (miss(a), a.distance(key))
+                    .partial_cmp(&(miss(b), b.distance(key)))
+                    .unwrap()
+            })
+            .map(|zone| (zone, zone.take()))
+            .unwrap()
+    }
+}
+
+impl<'a> Voice<'a> for Instrument {
+    /// Play a note as if struck at full velocity.
+    fn iter_freq(&'a self, freq: f32, rate: u32) -> Box<Signal<'a>> {
+        self.iter_note(freq, 127, rate)
+    }
+
+    fn iter_note(&'a self, freq: f32, velocity: u8, rate: u32) -> Box<Signal<'a>> {
+        let (zone, sloop) = self.choose(freq, velocity);
+        let samples = sloop.iter_freq(freq, rate);
+        match zone.env {
+            Some(ref env) => Box::new(ZoneNote {
+                samples,
+                envelope: Envelope::new(env, rate),
+            }),
+            None => Box::new(samples),
+        }
+    }
+}
+
+/// A note from a zone with its own envelope.
+struct ZoneNote<'a> {
+    samples: Samples<'a>,
+    envelope: Envelope<'a>,
+}
+
+impl Iterator for ZoneNote<'_> {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        let e = self.envelope.next()?;
+        Some(e * self.samples.next()?)
+    }
+}
+
+impl Stream for ZoneNote<'_> {
+    fn release(&mut self) {
+        self.envelope.release();
+        self.samples.release();
+    }
+
+    fn retrigger(&mut self) {
+        self.envelope.retrigger();
+        self.samples.retrigger();
+    }
+}
+
+#[cfg(test)]
+// A short loop with the given root key.
+fn test_loop(root: f32) -> Loop {
+    let meta = SampleMeta {
+        root: Some(root),
+        sustain: Some(0..100),
+        ..SampleMeta::default()
+    };
+    Loop::from_sample(&[0.0; 100], DEFAULT_SAMPLE_RATE, &meta)
+}
+
+#[test]
+// Check that notes go to the zone for their key and
+// velocity, or failing that to the nearest root, and that
+// alternates take turns.
+fn test_choose() {
+    let freq = |key: f32| 440.0 * f32::powf(2.0, (key - 69.0) / 12.0);
+    let instrument = Instrument::new(vec![
+        Zone::new(48..=59, 0..=63, vec![test_loop(55.0)]),
+        Zone::new(48..=59, 64..=127, vec![test_loop(54.0)]),
+        Zone::new(60..=71, 0..=127, vec![test_loop(65.0), test_loop(65.0)]),
+    ]);
+    let root = |key: f32, vel: u8| key_of(instrument.choose(freq(key), vel).1.freq().unwrap());
+    assert!((root(50.0, 30) - 55.0).abs() < 1.0e-3);
+    assert!((root(50.0, 100) - 54.0).abs() < 1.0e-3);
+    // Out of range: nearest root wins.
+    assert!((root(90.0, 30) - 65.0).abs() < 1.0e-3);
+    assert!((root(20.0, 30) - 55.0).abs() < 1.0e-3);
+
+    let a = instrument.choose(freq(64.0), 10).1 as *const Loop;
+    let b = instrument.choose(freq(64.0), 10).1 as *const Loop;
+    let c = instrument.choose(freq(64.0), 10).1 as *const Loop;
+    assert_ne!(a, b);
+    assert_eq!(a, c);
+}
+
+#[test]
+// Check that zones are made from metadata, or split between
+// roots, with matching ranges grouped as alternates.
+fn test_from_samples() {
+    let soft = SampleMeta {
+        velocities: Some(0..=63),
+        ..SampleMeta::default()
+    };
+    let instrument = Instrument::from_samples(vec![
+        (test_loop(60.0), SampleMeta::default()),
+        (test_loop(72.0), SampleMeta::default()),
+        (test_loop(72.0), SampleMeta::default()),
+        (test_loop(48.0), soft),
+    ]);
+    let zones = instrument.zones();
+    assert_eq!(3, zones.len());
+    assert_eq!(55..=66, zones[0].keys);
+    assert_eq!(67..=127, zones[1].keys);
+    assert_eq!(2, zones[1].loops.len());
+    assert_eq!(0..=54, zones[2].keys);
+    assert_eq!(0..=63, zones[2].velocities);
+}
+
+#[test]
+// Check that roots less than a semitone apart each get a
+// key, and roots rounding to one key alternate.
+fn test_from_samples_close() {
+    let instrument = Instrument::from_samples(vec![
+        (test_loop(60.2), SampleMeta::default()),
+        (test_loop(60.6), SampleMeta::default()),
+        (test_loop(60.9), SampleMeta::default()),
+        (test_loop(64.0), SampleMeta::default()),
+    ]);
+    let zones = instrument.zones();
+    assert_eq!(3, zones.len());
+    assert!(zones.iter().all(|z| !z.keys.is_empty()));
+    assert_eq!(0..=60, zones[0].keys);
+    assert_eq!(61..=62, zones[1].keys);
+    assert_eq!(2, zones[1].loops.len());
+    assert_eq!(63..=127, zones[2].keys);
+}
+
+#[test]
+// Check that a zone envelope shapes and ends its notes.
+fn test_zone_envelope() {
+    let meta = SampleMeta {
+        root: Some(69.0),
+        sustain: Some(0..100),
+        ..SampleMeta::default()
+    };
+    let sloop = Loop::from_sample(&[0.5; 100], DEFAULT_SAMPLE_RATE, &meta);
+    let plain = sloop
+        .iter_freq(440.0, DEFAULT_SAMPLE_RATE)
+        .nth(999)
+        .unwrap();
+    let env = Breakpoints::dahdsr(0.0, 0.001, 0.0, 0.0, 0.5, 0.001);
+    let zone = Zone::new(0..=127, 0..=127, vec![sloop]).with_envelope(env);
+    let instrument = Instrument::new(vec![zone]);
+    assert_eq!(0.001, instrument.release_time());
+
+    let mut note = instrument.iter_note(440.0, 100, DEFAULT_SAMPLE_RATE);
+    let held: Vec<f32> = note.by_ref().take(1000).collect();
+    assert!(held[0] < 0.1);
+    assert!((held[999] - 0.5 * plain).abs() < 1.0e-3);
+    note.release();
+    let rest = note.count();
+    assert!(rest > 0 && rest < 100, "{}", rest);
+}
@@ -5,11 +5,20 @@
 
 //! Educational music synthesizer.
 
+mod dynamics;
 mod envelope;
+mod fm;
+mod instrument;
 mod midi;
 mod mixer;
+mod noise;
+mod render;
+mod resampler;
 mod sampler;
+mod sfz;
+mod smf;
 mod wave;
+mod wavetable;
 mod wavio;
 
 #[cfg(feature = "cpal")]
@@ -22,18 +31,28 @@ use play_cpal as play;
 #[cfg(feature = "portaudio-rs")]
 use play_portaudio_rs as play;
 
+pub use dynamics::*;
 pub use envelope::*;
+pub use fm::*;
+pub use instrument::*;
 pub use midi::*;
 pub use mixer::*;
+pub use noise::*;
 pub use play::*;
+pub use render::*;
+pub use resampler::*;
 pub use sampler::*;
+pub use sfz::*;
+pub use smf::*;
 pub use wave::*;
+pub use wavetable::*;
 pub use wavio::*;
 
-/// The audio sample rate is currently fixed at 48000
-/// samples per second. This constant will be made a
-/// parameter somehow in some future crate version.
-pub const SAMPLE_RATE: u32 = 48_000;
+/// The audio sample rate asked for unless told otherwise,
+/// in samples per second. The rate actually used is set at
+/// runtime, as negotiated with the output device, and is
+/// passed to each voice when it starts a note.
+pub const DEFAULT_SAMPLE_RATE: u32 = 48_000;
 
 /// The number of samples we want buffered. Smaller is
 /// better, until the underruns start.
@@ -41,13 +60,32 @@ pub const WANT_BUFSIZE: u32 = 256;
 
 /// A signal is a stream of samples that can be sent across
 /// threads.
-type Signal<'a> = dyn Iterator<Item = f32> + Send + 'a;
+type Signal<'a> = dyn Stream + 'a;
+
+/// The stream of samples for a single note. Streams are told
+/// when their note is released, in case they have envelopes
+/// of their own.
+pub trait Stream: Iterator<Item = f32> + Send {
+    /// Enter the release phase. By default, do nothing.
+    fn release(&mut self) {}
+
+    /// The note's key has been struck again while it was
+    /// still sounding. By default, do nothing.
+    fn retrigger(&mut self) {}
+}
 
 /// All voices run as iterators producing `f32`. This trait
 /// allows a voice to generically produce an iterator for
-/// a given note.
+/// a given note at a given sample rate.
 pub trait Voice<'a> {
-    fn iter_freq(&'a self, freq: f32) -> Box<Signal<'a>>;
+    fn iter_freq(&'a self, freq: f32, rate: u32) -> Box<Signal<'a>>;
+
+    /// Produce an iterator for a note struck with the given
+    /// MIDI key velocity. By default the velocity is left to
+    /// the note's envelope.
+    fn iter_note(&'a self, freq: f32, _velocity: u8, rate: u32) -> Box<Signal<'a>> {
+        self.iter_freq(freq, rate)
+    }
 }
 
 /// Wrapper struct for player stream, to hold onto it until
@@ -13,58 +13,195 @@ use std::sync::{Arc, Mutex};
 // This should be replaced with `std::thread::Scope`
 // when that feature is stabilized.
 //use crossbeam::thread::scope;
-use wmidi::MidiMessage::*;
+use wmidi::MidiMessage::{self, *};
 
 use rustsy::*;
 
 fn main() {
     // Parse arguments.
     let args = argparse::args();
-    let kbd = args.keyboard;
+    if args.list_devices {
+        for line in list_devices().unwrap() {
+            println!("{}", line);
+        }
+        return;
+    }
+    if args.render.is_some() && args.script.is_none() && args.midi_file.is_none() {
+        eprintln!("rustsy: --render needs --script or --midi-file");
+        std::process::exit(1);
+    }
 
-    let voice: Box<dyn Voice<'_>> = if let Some(ref sample) = args.sampler {
-        // Get a signal from a WAV file, make a loop.
-        let sound = get_sample(&sample).unwrap();
-        Box::new(Loop::new(&sound))
+    let mut adsr = Breakpoints::adsr(0.03, 0.03, 0.8, 0.03);
+    let voice: Box<dyn Voice<'_>> = if !args.sampler.is_empty() {
+        // Get signals from WAV files, make loops and map
+        // them across the keyboard.
+        let mut samples = Vec::new();
+        for sample in &args.sampler {
+            let (sound, meta) = match get_sample(sample, args.channel, args.rate) {
+                Ok(sound) => sound,
+                Err(e) => {
+                    eprintln!("rustsy: {}: {}", sample.display(), e);
+                    std::process::exit(1);
+                }
+            };
+            let sloop = Loop::from_sample(&sound, args.rate, &meta)
+                .with_crossfade(args.crossfade)
+                .with_release_tail(args.release_tail)
+                .with_quality(args.quality);
+            samples.push((sloop, meta));
+        }
+        let instrument = Instrument::from_samples(samples);
+        if args.release_tail {
+            // The samples have their own attack and release:
+            // just gate the note, leaving room for the tail.
+            adsr = Breakpoints::adsr(0.0, 0.0, 1.0, instrument.release_time());
+        }
+        Box::new(instrument)
+    } else if let Some(ref sfz) = args.sfz {
+        let instrument = match load_sfz(sfz, args.rate) {
+            Ok(instrument) => instrument.with_quality(args.quality),
+            Err(e) => {
+                eprintln!("rustsy: {}", e);
+                std::process::exit(1);
+            }
+        };
+        // The regions have their own envelopes: just gate
+        // the note, leaving room for their release.
+        adsr = Breakpoints::adsr(0.0, 0.0, 1.0, instrument.release_time());
+        Box::new(instrument)
+    } else if args.wave.as_deref() == Some("fm") {
+        let fm = match FmGen::patch(&args.patch) {
+            Some(fm) => fm,
+            None => {
+                eprintln!(
+                    "rustsy: unknown FM patch {}: use {}",
+                    args.patch,
+                    PATCHES.join(", ")
+                );
+                std::process::exit(1);
+            }
+        };
+        // The operators have their own envelopes: just gate
+        // the note, leaving room for operator release.
+        adsr = Breakpoints::adsr(0.0, 0.0, 1.0, fm.release_time());
+        Box::new(fm)
+    } else if args.wave.as_deref() == Some("table") {
+        if args.frames.is_empty() {
+            use WaveShape::*;
+            Box::new(Wavetable::from_shapes(&[Sine, Tri, Saw, Square]))
+        } else {
+            Box::new(Wavetable::load(&args.frames).unwrap())
+        }
     } else if let Some(ref wave) = args.wave {
-        match wave.as_str() {
-            "sin" | "sine" => Box::new(WaveGen::new(WaveShape::Sine)),
-            "square" => Box::new(WaveGen::new(WaveShape::Square)),
-            "saw" | "sawtooth" => Box::new(WaveGen::new(WaveShape::Saw)),
-            "tri" | "triangle" => Box::new(WaveGen::new(WaveShape::Tri)),
-            _ => panic!("invalid wave shape: use sine"),
+        let shape = match wave.as_str() {
+            "sin" | "sine" => WaveShape::Sine,
+            "square" => WaveShape::Square,
+            "saw" | "sawtooth" => WaveShape::Saw,
+            "tri" | "triangle" => WaveShape::Tri,
+            "pulse" => WaveShape::Pulse,
+            "white" | "noise" => WaveShape::White,
+            "pink" => WaveShape::Pink,
+            "brown" | "red" => WaveShape::Brown,
+            _ => panic!(
+                "invalid wave shape: use sine, square, saw, tri, pulse, white, pink, brown, fm or table"
+            ),
+        };
+        let mode = if args.raw {
+            WaveMode::Raw
+        } else {
+            WaveMode::BandLimited
+        };
+        let mut gen = WaveGen::with_mode(shape, mode).with_duty(args.duty);
+        if args.pwm_depth != 0.0 {
+            gen = gen.with_pwm(Pwm::Lfo {
+                rate: args.pwm_rate,
+                depth: args.pwm_depth,
+            });
+        }
+        if let Some(seed) = args.seed {
+            gen = gen.with_seed(seed);
         }
+        Box::new(gen)
     } else {
-        panic!("no valid voice: use --sampler or --wave");
+        panic!("no valid voice: use --sampler, --sfz or --wave");
     };
 
-    let adsr = Box::new(ADSR::new(0.03, 0.03, 0.8, 0.03));
-    let adsr: &'static ADSR = Box::leak(adsr);
+    if let Some(c) = args.curve {
+        adsr = adsr.with_curves(c, c, c);
+    }
+    let env: &'static Breakpoints = Box::leak(Box::new(adsr));
+    let velocity = Velocity {
+        curve: args.velocity_curve,
+        attack: args.velocity_attack,
+        cutoff: args.velocity_cutoff,
+    };
     let voice: &'static dyn Voice<'_> = Box::leak(voice);
 
     // Start the synth.
-    let mixer = Arc::new(Mutex::new(Mixer::default()));
-    let _stream = play(Arc::clone(&mixer)).unwrap();
+    let limiter = Limiter::new()
+        .with_threshold(args.limit_threshold)
+        .with_ratio(args.limit_ratio)
+        .with_attack(args.limit_attack)
+        .with_release(args.limit_release)
+        .with_lookahead(args.limit_lookahead);
+    let mixer = Mixer::new(args.polyphony.get(), args.steal)
+        .with_retrigger(args.retrigger)
+        .with_rate(args.rate)
+        .with_limiter(limiter);
 
-    let keystream = read_keys(&kbd).unwrap();
-    for kev in keystream {
-        match kev {
-            NoteOn(_c, key, _vel) => {
-                let mut gmixer = mixer.lock().unwrap();
-                let note = Note::new(voice, adsr, key.to_freq_f32());
-                let key = usize::from(key as u8);
-                gmixer.borrow_mut().add_key(key, note);
-                drop(gmixer);
-            }
-            NoteOff(_c, key, _vel) => {
-                let mut gmixer = mixer.lock().unwrap();
-                let key = usize::from(key as u8);
-                if let Some(note) = gmixer.borrow_mut().get_key_mut(key) {
-                    note.release();
-                }
-                drop(gmixer);
-            }
-            _ => (),
+    // Start and stop notes.
+    let key_event = |mixer: &mut Mixer<Note<'static>>, kev: &MidiMessage| match *kev {
+        NoteOn(_c, key, vel) => {
+            let note = Note::new(
+                voice,
+                env,
+                key.to_freq_f32(),
+                u8::from(vel),
+                &velocity,
+                mixer.rate(),
+            );
+            mixer.add_key(usize::from(key as u8), note);
+        }
+        NoteOff(_c, key, _vel) => {
+            mixer.release_key(usize::from(key as u8));
         }
+        _ => (),
+    };
+
+    // Timed events, if not playing from a keyboard.
+    let events = if let Some(ref script) = args.script {
+        Some(read_script(script).unwrap())
+    } else {
+        args.midi_file
+            .as_ref()
+            .map(|midi_file| read_midi_file(midi_file).unwrap())
+    };
+
+    if let Some(ref wav) = args.render {
+        let events = events.expect("render events checked above");
+        let mut mixer = mixer;
+        render(&mut mixer, &events, wav, key_event).unwrap();
+        return;
+    }
+
+    let mixer = Arc::new(Mutex::new(mixer));
+    let _stream = match play(Arc::clone(&mixer), args.device.as_deref()) {
+        Ok(stream) => stream,
+        Err(e) => {
+            eprintln!("rustsy: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    if let Some(events) = events {
+        play_events(&mixer, &events, key_event);
+        return;
+    }
+
+    let keystream = read_keys(args.keyboard.as_ref().unwrap()).unwrap();
+    for kev in keystream {
+        let mut gmixer = mixer.lock().unwrap();
+        key_event(gmixer.borrow_mut(), &kev);
+        drop(gmixer);
     }
 }
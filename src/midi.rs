@@ -8,12 +8,15 @@
 use std::convert::TryFrom;
 use std::error::Error;
 use std::io;
-use std::sync::mpsc;
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::{Duration, Instant};
 
 use midir::MidiInput;
 use wmidi::MidiMessage::*;
 use wmidi::*;
 
+use crate::{Event, Mixer};
+
 /// Read and process key events from a MIDI keyboard with the
 /// given name.
 pub fn read_keys(port_name: &str) -> Result<mpsc::Receiver<MidiMessage<'static>>, Box<dyn Error>> {
@@ -67,3 +70,25 @@ pub fn read_keys(port_name: &str) -> Result<mpsc::Receiver<MidiMessage<'static>>
     std::mem::forget(handler?);
     Ok(receiver)
 }
+
+/// Play timed key events in real time, passing each to
+/// `handler` with the mixer locked when its time comes.
+/// Returns once the last event has been handled and the
+/// mixer has fallen silent.
+pub fn play_events<N, F>(mixer: &Arc<Mutex<Mixer<N>>>, events: &[Event], mut handler: F)
+where
+    F: FnMut(&mut Mixer<N>, &MidiMessage<'static>),
+{
+    let start = Instant::now();
+    for event in events {
+        let when = start + Duration::from_secs_f32(event.time);
+        if let Some(wait) = when.checked_duration_since(Instant::now()) {
+            std::thread::sleep(wait);
+        }
+        handler(&mut mixer.lock().unwrap(), &event.message);
+    }
+    // Let the releases play out.
+    while !mixer.lock().unwrap().is_empty() {
+        std::thread::sleep(Duration::from_millis(10));
+    }
+}
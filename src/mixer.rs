@@ -3,70 +3,260 @@
 // Please see the file LICENSE in the source
 // distribution of this software for license terms.
 
-// *Historical note:* the use of a `HashMap` instead of a
-// `Vec` as a notemap was originally a workaround for
-// `Vec::retain()` passing `&T` instead of `&mut T`.
-// Once [[Vec::retain_mut()]] is stabilized (issue #90829),
-// one could undo this decision, but it seems fine for now.
-use std::collections::HashMap;
+// *Historical note:* a `HashMap` from key to note was
+// originally used as the notemap, as a workaround for
+// `Vec::retain()` passing `&T` instead of `&mut T`. Now
+// that `Vec::retain_mut()` is stable, and a key may have
+// several notes sounding, the notemap is a `Vec` in
+// starting order.
+use std::str::FromStr;
+
+use crate::*;
+
+/// A note as seen by the mixer, for voice management.
+pub trait MixerNote {
+    /// Current amplitude of the note.
+    fn level(&self) -> f32;
+    /// True once the note has been released.
+    fn is_released(&self) -> bool;
+    /// Enter the release phase.
+    fn release(&mut self);
+    /// Start the note over from where it is, as when its
+    /// key is struck again, taking on the velocity and sound
+    /// of the new strike's `note`.
+    fn retrigger(&mut self, note: Self)
+    where
+        Self: Sized;
+    /// Fade the note out to silence over `time` seconds,
+    /// then finish.
+    fn fade_out(&mut self, time: f32);
+}
+
+/// What to do when a key is struck while it already has a
+/// note sounding.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Retrigger {
+    /// Restart the newest note from its current level, at
+    /// the new velocity.
+    Restart,
+    /// Release the old notes and start a new one alongside.
+    Layer,
+    /// Ignore the key if its newest note is still held;
+    /// otherwise start a new one alongside.
+    Ignore,
+}
+
+impl FromStr for Retrigger {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "restart" => Ok(Retrigger::Restart),
+            "layer" => Ok(Retrigger::Layer),
+            "ignore" => Ok(Retrigger::Ignore),
+            _ => Err(format!(
+                "invalid retrigger policy {}: use restart, layer or ignore",
+                s
+            )),
+        }
+    }
+}
+
+/// Which note to take when the polyphony limit is reached.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Steal {
+    /// The note that started first.
+    Oldest,
+    /// The note with the lowest current level.
+    Quietest,
+    /// The note with the lowest key.
+    Lowest,
+    /// The note with the highest key.
+    Highest,
+    /// The oldest released note, or the oldest note if none
+    /// are released.
+    Released,
+}
+
+impl FromStr for Steal {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "oldest" => Ok(Steal::Oldest),
+            "quietest" => Ok(Steal::Quietest),
+            "lowest" => Ok(Steal::Lowest),
+            "highest" => Ok(Steal::Highest),
+            "released" => Ok(Steal::Released),
+            _ => Err(format!(
+                "invalid steal policy {}: use oldest, quietest, lowest, highest or released",
+                s
+            )),
+        }
+    }
+}
 
 /// A sample "mixer" that adds values from streams of
 /// samples (currently always associated with a key) and
-/// scales appropriately to get output samples.  Implemented
+/// runs the sum through a limiter to get output samples.  Implemented
 /// as an unbounded iterator: will return `Some(0.0)` when
 /// no sample streams are available.
 pub struct Mixer<N> {
-    /// Held key indexes and generators.
-    pub held: HashMap<usize, N>,
-    /// Current mixer gain value.
-    gain: f32,
+    /// Held key indexes and generators, oldest first.
+    pub held: Vec<(usize, N)>,
+    /// Stolen notes that are fading out.
+    stolen: Vec<N>,
+    /// Maximum number of held notes.
+    polyphony: usize,
+    /// Voice stealing policy.
+    steal: Steal,
+    /// Key retrigger policy.
+    retrigger: Retrigger,
+    /// Output dynamics.
+    limiter: Limiter,
+    /// Sample rate.
+    rate: u32,
 }
 
-/// Max voices before AGC kicks in.
-const AGC_VOICES: usize = 8;
-/// Mixer gain before AGC kicks in.
-const LINEAR_GAIN: f32 = 0.1;
+/// Gain applied to each note before limiting.
+const MIX_GAIN: f32 = 0.1;
+/// Default polyphony limit.
+pub const POLYPHONY: usize = 32;
+/// Fade-out time of a stolen note in seconds. Long enough
+/// not to click.
+const STEAL_FADE: f32 = 0.005;
 
 impl<N> Mixer<N> {
-    /// Remove a stream from the mixer by key.
+    /// Make a new mixer with the given polyphony limit and
+    /// stealing policy.
+    pub fn new(polyphony: usize, steal: Steal) -> Self {
+        assert!(polyphony > 0, "mixer needs polyphony");
+        Self {
+            held: Vec::with_capacity(polyphony),
+            stolen: Vec::new(),
+            polyphony,
+            steal,
+            retrigger: Retrigger::Restart,
+            limiter: Limiter::new(),
+            rate: DEFAULT_SAMPLE_RATE,
+        }
+    }
+
+    /// Set the sample rate.
+    pub fn with_rate(mut self, rate: u32) -> Self {
+        self.set_rate(rate);
+        self
+    }
+
+    /// Change the sample rate, as when the output device
+    /// asks for a different one. Notes already sounding keep
+    /// the rate they were started with.
+    pub fn set_rate(&mut self, rate: u32) {
+        self.rate = rate;
+        self.limiter.set_rate(rate);
+    }
+
+    /// Sample rate for new notes.
+    pub fn rate(&self) -> u32 {
+        self.rate
+    }
+
+    /// Set the key retrigger policy.
+    pub fn with_retrigger(mut self, retrigger: Retrigger) -> Self {
+        self.retrigger = retrigger;
+        self
+    }
+
+    /// Set the output limiter.
+    pub fn with_limiter(mut self, mut limiter: Limiter) -> Self {
+        limiter.set_rate(self.rate);
+        self.limiter = limiter;
+        self
+    }
+
+    /// Remove the streams for a key from the mixer.
     pub fn remove_key(&mut self, key: usize) {
-        self.held.remove(&key);
+        self.held.retain(|(k, _)| *k != key);
     }
 
+    /// The newest stream for a key.
     pub fn get_key_mut(&mut self, key: usize) -> Option<&mut N> {
-        self.held.get_mut(&key)
+        self.held
+            .iter_mut()
+            .rev()
+            .find(|(k, _)| *k == key)
+            .map(|(_, n)| n)
+    }
+
+    /// True if no streams are sounding.
+    pub fn is_empty(&self) -> bool {
+        self.held.is_empty() && self.stolen.is_empty()
+    }
+
+    /// Output delay in samples.
+    pub fn latency(&self) -> usize {
+        self.limiter.latency()
     }
 
     /// Remove all streams from the mixer.
     pub fn clear(&mut self) {
         self.held.clear();
+        self.stolen.clear();
     }
+}
 
-    /// Adjust the gain to avoid clipping while preserving
-    /// some linearity. Essentially a compressor.
-    fn agc(&mut self) {
-        let nstreams = self.held.len();
-        self.gain = if nstreams <= AGC_VOICES {
-            LINEAR_GAIN
-        } else {
-            LINEAR_GAIN * AGC_VOICES as f32 / nstreams as f32
-        };
+impl<N: MixerNote> Mixer<N> {
+    /// Add a stream to the mixer, following the retrigger
+    /// policy if the key is already sounding, and stealing
+    /// a held stream if the polyphony limit has been
+    /// reached.
+    pub fn add_key(&mut self, key: usize, note: N) {
+        let retrigger = self.retrigger;
+        if let Some(old) = self.get_key_mut(key) {
+            match retrigger {
+                Retrigger::Restart => {
+                    old.retrigger(note);
+                    return;
+                }
+                Retrigger::Ignore if !old.is_released() => return,
+                Retrigger::Ignore => (),
+                Retrigger::Layer => self.release_key(key),
+            }
+        }
+        if self.held.len() >= self.polyphony {
+            let (_, mut stolen) = self.held.remove(self.victim());
+            stolen.fade_out(STEAL_FADE);
+            self.stolen.push(stolen);
+        }
+        self.held.push((key, note));
     }
 
-    /// Add a stream to the mixer.
-    pub fn add_key(&mut self, key: usize, note: N) {
-        let was_held = self.held.insert(key, note);
-        assert!(was_held.is_none());
-        self.agc();
+    /// Release the streams for a key.
+    pub fn release_key(&mut self, key: usize) {
+        for (_, note) in self.held.iter_mut().filter(|(k, _)| *k == key) {
+            note.release();
+        }
+    }
+
+    // Index of the held note to steal.
+    fn victim(&self) -> usize {
+        let held = self.held.iter().enumerate();
+        let index = match self.steal {
+            Steal::Oldest => None,
+            Steal::Lowest => held.min_by_key(|(_, (k, _))| *k),
+            Steal::Highest => held.max_by_key(|(_, (k, _))| *k),
+            Steal::Quietest => {
+                held.min_by(|(_, (_, a)), (_, (_, b))| a.level().total_cmp(&b.level()))
+            }
+            Steal::Released => held.clone().find(|(_, (_, n))| n.is_released()),
+        };
+        index.map(|(i, _)| i).unwrap_or(0)
     }
 }
 
 impl<N> Default for Mixer<N> {
     fn default() -> Self {
-        Self {
-            held: HashMap::with_capacity(128),
-            gain: LINEAR_GAIN,
-        }
+        Self::new(POLYPHONY, Steal::Released)
     }
 }
 
@@ -82,18 +272,199 @@ where
     // input streams are infinite, but the output stream is.
     fn next(&mut self) -> Option<f32> {
         let mut result = 0.0;
-        let mut finished = Vec::new();
-        for (k, st) in self.held.iter_mut() {
-            let s = st.next();
-            match s {
-                Some(s) => result += s,
-                None => finished.push(*k),
+        let mut mix = |st: &mut N| match st.next() {
+            Some(s) => {
+                result += s;
+                true
+            }
+            None => false,
+        };
+        self.held.retain_mut(|(_, st)| mix(st));
+        self.stolen.retain_mut(mix);
+        Some(self.limiter.process(result * MIX_GAIN))
+    }
+}
+
+/// Constant-level note for testing the mixer.
+#[cfg(test)]
+struct TestNote {
+    level: f32,
+    released: bool,
+    retriggered: bool,
+    fade: Option<f32>,
+}
+
+#[cfg(test)]
+impl TestNote {
+    fn new(level: f32) -> Self {
+        Self {
+            level,
+            released: false,
+            retriggered: false,
+            fade: None,
+        }
+    }
+}
+
+#[cfg(test)]
+impl MixerNote for TestNote {
+    fn level(&self) -> f32 {
+        self.level
+    }
+
+    fn is_released(&self) -> bool {
+        self.released
+    }
+
+    fn release(&mut self) {
+        self.released = true;
+    }
+
+    fn retrigger(&mut self, note: Self) {
+        self.level = note.level;
+        self.released = false;
+        self.retriggered = true;
+    }
+
+    fn fade_out(&mut self, _time: f32) {
+        self.fade = Some(1.0);
+    }
+}
+
+#[cfg(test)]
+impl Iterator for TestNote {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        match self.fade {
+            Some(g) if g <= 0.0 => None,
+            Some(g) => {
+                self.fade = Some(g - 0.25);
+                Some(g * self.level)
             }
+            None => Some(self.level),
         }
-        for k in finished {
-            self.remove_key(k);
+    }
+}
+
+#[test]
+// Check that each stealing policy takes the right note.
+fn test_steal_policy() {
+    let cases = [
+        (Steal::Oldest, 60),
+        (Steal::Quietest, 64),
+        (Steal::Lowest, 55),
+        (Steal::Highest, 67),
+        (Steal::Released, 67),
+    ];
+    for (steal, victim) in cases {
+        let mut mixer = Mixer::new(4, steal);
+        for (key, level) in [(60, 0.5), (67, 0.4), (55, 0.6), (64, 0.1)] {
+            mixer.add_key(key, TestNote::new(level));
         }
-        self.agc();
-        Some(result * self.gain)
+        mixer.release_key(67);
+        mixer.add_key(72, TestNote::new(1.0));
+        let keys: Vec<usize> = mixer.held.iter().map(|(k, _)| *k).collect();
+        assert_eq!(4, keys.len());
+        assert!(!keys.contains(&victim), "{:?}", steal);
+        assert!(keys.contains(&72));
+    }
+}
+
+#[test]
+// Check each retrigger policy on a held key, and on a key
+// whose note is releasing.
+fn test_retrigger() {
+    let mixer = |retrigger| {
+        let mut mixer = Mixer::new(8, Steal::Oldest).with_retrigger(retrigger);
+        mixer.add_key(60, TestNote::new(0.5));
+        mixer.add_key(60, TestNote::new(1.0));
+        mixer
+    };
+
+    let mut restart = mixer(Retrigger::Restart);
+    assert_eq!(1, restart.held.len());
+    assert!(restart.held[0].1.retriggered);
+    // The restarted note takes the new strike's velocity.
+    assert_eq!(1.0, restart.held[0].1.level);
+
+    let mut layer = mixer(Retrigger::Layer);
+    assert_eq!(2, layer.held.len());
+    assert!(layer.held[0].1.released);
+    assert!(!layer.held[1].1.released);
+
+    let mut ignore = mixer(Retrigger::Ignore);
+    assert_eq!(1, ignore.held.len());
+    assert!(!ignore.held[0].1.retriggered);
+    ignore.release_key(60);
+    ignore.add_key(60, TestNote::new(1.0));
+    assert_eq!(2, ignore.held.len());
+
+    // Newest note for the key.
+    assert_eq!(1.0, layer.get_key_mut(60).unwrap().level);
+    for m in [&mut restart, &mut layer, &mut ignore] {
+        m.release_key(60);
+        assert!(m.held.iter().all(|(_, n)| n.released));
+        m.remove_key(60);
+        assert!(m.held.is_empty());
+    }
+}
+
+#[test]
+// Check that a restarted note takes on the velocity of the
+// new strike, climbing from where the old one was.
+fn test_retrigger_velocity() {
+    let voice = WaveGen::new(WaveShape::Square);
+    let env = Breakpoints::adsr(0.01, 0.0, 1.0, 0.01);
+    let response = Velocity::default();
+    let note = |velocity| {
+        Note::new(
+            &voice,
+            &env,
+            100.0,
+            velocity,
+            &response,
+            DEFAULT_SAMPLE_RATE,
+        )
+    };
+    let mut mixer = Mixer::new(8, Steal::Oldest);
+    mixer.add_key(60, note(20));
+    mixer.by_ref().take(1000).for_each(drop);
+    let soft = mixer.held[0].1.level();
+
+    mixer.add_key(60, note(127));
+    assert_eq!(1, mixer.held.len());
+    mixer.next();
+    assert!((mixer.held[0].1.level() - soft).abs() < 0.01);
+    mixer.by_ref().take(1000).for_each(drop);
+    let loud = mixer.held[0].1.level();
+    assert!((loud - 1.0).abs() < 1.0e-3, "{} {}", soft, loud);
+}
+
+#[test]
+// Check that a stolen note fades out rather than stopping
+// dead, and is then dropped.
+fn test_steal_fade() {
+    let limiter = Limiter::new().with_lookahead(0.0);
+    let mut mixer = Mixer::new(1, Steal::Oldest).with_limiter(limiter);
+    mixer.add_key(60, TestNote::new(1.0));
+    assert_eq!(Some(MIX_GAIN), mixer.next());
+    mixer.add_key(62, TestNote::new(0.0));
+    let out: Vec<f32> = mixer.by_ref().take(6).collect();
+    assert!(out[..4].windows(2).all(|w| w[1] < w[0]));
+    assert_eq!(0.0, out[5]);
+    assert!(mixer.stolen.is_empty());
+}
+
+#[test]
+// Check that a pile of loud notes is limited rather than
+// clipped.
+fn test_mixer_limit() {
+    let mut mixer = Mixer::new(64, Steal::Oldest);
+    for key in 0..64 {
+        mixer.add_key(key, TestNote::new(1.0));
     }
+    assert!(mixer
+        .take(DEFAULT_SAMPLE_RATE as usize / 10)
+        .all(|s| s <= 1.0));
 }
@@ -0,0 +1,166 @@
+// Copyright © 2022 Bart Massey
+// [This program is licensed under the "MIT License"]
+// Please see the file LICENSE in the source
+// distribution of this software for license terms.
+
+//! Noise sources.
+//!
+//! All noise comes from a small seedable PRNG, so that a
+//! given seed always produces the same samples. This keeps
+//! offline renders reproducible.
+
+use crate::*;
+
+/// Deterministic pseudo-random number generator. This is
+/// SplitMix64: fast, tiny and plenty good for audio.
+#[derive(Debug, Clone)]
+pub struct Rng(u64);
+
+impl Rng {
+    /// Make a new generator from the given seed.
+    pub fn new(seed: u64) -> Self {
+        Self(seed)
+    }
+
+    /// Next 64 random bits.
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// Uniform random sample in `-1.0..1.0`.
+    pub fn uniform(&mut self) -> f32 {
+        // Top 24 bits: exactly representable as an f32.
+        let x = (self.next_u64() >> 40) as f32 / (1 << 24) as f32;
+        2.0 * x - 1.0
+    }
+}
+
+/// Number of rows in the Voss-McCartney pink noise
+/// generator. Each row covers an octave.
+const PINK_ROWS: usize = 16;
+
+/// Leak coefficient of the brown noise integrator. Keeps
+/// the random walk from wandering off.
+const BROWN_LEAK: f32 = 0.02;
+
+/// Gain bringing brown noise up to roughly unit peak.
+const BROWN_GAIN: f32 = 3.5;
+
+/// Running state of a noise source.
+pub(crate) enum Noise {
+    White(Rng),
+    Pink {
+        rng: Rng,
+        rows: [f32; PINK_ROWS],
+        sum: f32,
+        count: u32,
+    },
+    Brown {
+        rng: Rng,
+        level: f32,
+    },
+}
+
+impl Noise {
+    /// Noise source for the given shape, or `None` for
+    /// pitched shapes.
+    pub(crate) fn new(shape: WaveShape, seed: u64) -> Option<Self> {
+        let rng = Rng::new(seed);
+        match shape {
+            WaveShape::White => Some(Noise::White(rng)),
+            WaveShape::Pink => Some(Noise::Pink {
+                rng,
+                rows: [0.0; PINK_ROWS],
+                sum: 0.0,
+                count: 0,
+            }),
+            WaveShape::Brown => Some(Noise::Brown { rng, level: 0.0 }),
+            _ => None,
+        }
+    }
+}
+
+impl Iterator for Noise {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        let s = match self {
+            Noise::White(rng) => rng.uniform(),
+            Noise::Pink {
+                rng,
+                rows,
+                sum,
+                count,
+            } => {
+                // Voss-McCartney: row `k` is refreshed every
+                // `2**(k+1)` samples, picked by the trailing
+                // zeros of a counter. A fresh white sample
+                // fills in the top octave.
+                *count = count.wrapping_add(1);
+                let k = count.trailing_zeros() as usize;
+                if k < PINK_ROWS {
+                    let r = rng.uniform();
+                    *sum += r - rows[k];
+                    rows[k] = r;
+                }
+                // Scale to the RMS level of white noise.
+                (*sum + rng.uniform()) / f32::sqrt((PINK_ROWS + 1) as f32)
+            }
+            Noise::Brown { rng, level } => {
+                *level = (*level + BROWN_LEAK * rng.uniform()) / (1.0 + BROWN_LEAK);
+                BROWN_GAIN * *level
+            }
+        };
+        Some(s)
+    }
+}
+
+#[cfg(test)]
+// Energy of a signal in its first-difference (high) and
+// running-sum (low) forms, as a crude spectral tilt.
+fn tilt(shape: WaveShape) -> f32 {
+    let buf: Vec<f32> = Noise::new(shape, 1).unwrap().take(1 << 16).collect();
+    let hi: f32 = buf.windows(2).map(|w| (w[1] - w[0]).powi(2)).sum();
+    let lo: f32 = buf.windows(2).map(|w| (w[1] + w[0]).powi(2)).sum();
+    lo / hi
+}
+
+#[test]
+// Check that noise is reproducible from its seed, and that
+// different seeds give different noise.
+fn test_noise_seed() {
+    for shape in [WaveShape::White, WaveShape::Pink, WaveShape::Brown] {
+        let a: Vec<f32> = Noise::new(shape, 7).unwrap().take(1000).collect();
+        let b: Vec<f32> = Noise::new(shape, 7).unwrap().take(1000).collect();
+        let c: Vec<f32> = Noise::new(shape, 8).unwrap().take(1000).collect();
+        assert_eq!(a, b);
+        assert_ne!(a, c);
+    }
+    assert!(Noise::new(WaveShape::Sine, 7).is_none());
+}
+
+#[test]
+// Check that white noise is in range and centered, and that
+// pink and brown noise are successively darker.
+fn test_noise_color() {
+    let white: Vec<f32> = Noise::new(WaveShape::White, 1)
+        .unwrap()
+        .take(1 << 16)
+        .collect();
+    assert!(white.iter().all(|s| (-1.0..1.0).contains(s)));
+    let mean = white.iter().sum::<f32>() / white.len() as f32;
+    assert!(mean.abs() < 0.01);
+
+    let (w, p, b) = (
+        tilt(WaveShape::White),
+        tilt(WaveShape::Pink),
+        tilt(WaveShape::Brown),
+    );
+    assert!((w - 1.0).abs() < 0.1, "white tilt {}", w);
+    assert!(p > 2.0 * w, "pink tilt {}", p);
+    assert!(b > 2.0 * p, "brown tilt {}", b);
+}
@@ -6,85 +6,233 @@
 //! Synthesizer audio player.
 
 use std::error::Error;
-use std::io::{self, ErrorKind};
 use std::sync::{Arc, Mutex};
 
 use cpal::traits::*;
 
 use crate::*;
 
-/// Gather samples and post for playback.
-pub fn play<N>(mixer: Arc<Mutex<Mixer<N>>>) -> Result<Player<cpal::Stream>, Box<dyn Error>>
-where
-    N: Iterator<Item = f32> + Send + 'static,
-{
-    // Get the device.
-    let host = cpal::default_host();
-    let device = host
-        .default_output_device()
-        .ok_or_else(|| Box::new(io::Error::from(ErrorKind::ConnectionRefused)))?;
-
-    // Config matcher.
-    let target_rate = cpal::SampleRate(SAMPLE_RATE as u32);
-    let config_matcher = |device: &cpal::Device| {
-        for config_range in device.supported_output_configs()? {
-            if config_range.channels() != 1 {
-                continue;
-            }
-            if config_range.sample_format() != cpal::SampleFormat::I16 {
-                continue;
-            }
-            if config_range.min_sample_rate() > target_rate {
-                continue;
-            }
-            if config_range.max_sample_rate() < target_rate {
+// Describe a device's output configs, one per line.
+fn describe_configs(device: &cpal::Device) -> Result<Vec<String>, Box<dyn Error>> {
+    let configs = device
+        .supported_output_configs()?
+        .map(|c| {
+            format!(
+                "{} ch {:?} {}-{} Hz",
+                c.channels(),
+                c.sample_format(),
+                c.min_sample_rate().0,
+                c.max_sample_rate().0,
+            )
+        })
+        .collect();
+    Ok(configs)
+}
+
+/// List the output devices of every available host, with
+/// the configs each supports. A host or device that cannot
+/// be queried is listed with the error, so that the rest
+/// still are.
+pub fn list_devices() -> Result<Vec<String>, Box<dyn Error>> {
+    let default_host = cpal::default_host().id();
+    let mut lines = Vec::new();
+    for id in cpal::available_hosts() {
+        let mark = if id == default_host { " (default)" } else { "" };
+        lines.push(format!("{}:{}", id.name(), mark));
+        let devices = || -> Result<_, Box<dyn Error>> {
+            let host = cpal::host_from_id(id)?;
+            let default = host.default_output_device().and_then(|d| d.name().ok());
+            Ok((host.output_devices()?, default))
+        };
+        let (devices, default) = match devices() {
+            Ok(devices) => devices,
+            Err(e) => {
+                lines.push(format!("  (devices unavailable: {})", e));
                 continue;
             }
-            let buffer_size = match config_range.buffer_size() {
-                cpal::SupportedBufferSize::Range { min, max } => {
-                    eprintln!("buffer size {}..{}", min, max);
-                    cpal::BufferSize::Fixed((*min).max(WANT_BUFSIZE.min(*max)))
-                }
-                cpal::SupportedBufferSize::Unknown => cpal::BufferSize::Default,
+        };
+        for (i, device) in devices.enumerate() {
+            let name = match device.name() {
+                Ok(name) => name,
+                Err(e) => format!("(name unavailable: {})", e),
             };
-            let config = cpal::StreamConfig {
-                channels: 1,
-                sample_rate: target_rate,
-                buffer_size,
+            let mark = if Some(&name) == default.as_ref() {
+                " (default)"
+            } else {
+                ""
             };
-            // let config = config_range.with_sample_rate(target_rate);
-            // let config = config.config();
-            eprintln!("config {:#?}", config);
-            return Ok(config);
+            lines.push(format!("  {}: {}{}", i, name, mark));
+            match describe_configs(&device) {
+                Ok(configs) => {
+                    for config in configs {
+                        lines.push(format!("      {}", config));
+                    }
+                }
+                Err(e) => lines.push(format!("      (configs unavailable: {})", e)),
+            }
         }
-        Err(cpal::SupportedStreamConfigsError::DeviceNotAvailable)
+    }
+    Ok(lines)
+}
+
+/// Find an output device by `[host:]name` or `[host:]index`,
+/// as shown by [list_devices]. With no host the default host
+/// is used.
+fn find_device(spec: &str) -> Result<cpal::Device, Box<dyn Error>> {
+    let host_named = |name: &str| {
+        cpal::available_hosts()
+            .into_iter()
+            .find(|id| id.name().eq_ignore_ascii_case(name))
+    };
+    let (host, name) = match spec.split_once(':').map(|(h, n)| (host_named(h), n)) {
+        Some((Some(id), name)) => (cpal::host_from_id(id)?, name),
+        // Device names may contain colons.
+        _ => (cpal::default_host(), spec),
+    };
+    let mut devices = host.output_devices()?;
+    let device = match name.parse::<usize>() {
+        Ok(index) => devices.nth(index),
+        Err(_) => devices.find(|d| d.name().map(|n| n == name).unwrap_or(false)),
+    };
+    device.ok_or_else(|| {
+        format!(
+            "no output device {} on host {}: try --list-devices",
+            name,
+            host.id().name()
+        )
+        .into()
+    })
+}
+
+/// Gather samples and post for playback on the named device,
+/// or on the default device. The mixer's sample rate is
+/// used if the device supports it; otherwise the mixer is
+/// switched to the nearest rate the device does support.
+pub fn play<N>(
+    mixer: Arc<Mutex<Mixer<N>>>,
+    device: Option<&str>,
+) -> Result<Player<cpal::Stream>, Box<dyn Error>>
+where
+    N: Iterator<Item = f32> + Send + 'static,
+{
+    // Get the device.
+    let device = match device {
+        Some(spec) => find_device(spec)?,
+        None => cpal::default_host()
+            .default_output_device()
+            .ok_or("no default output device: try --list-devices")?,
     };
 
     // Try to find a matching config.
-    let config = config_matcher(&device)?;
+    let rate = mixer.lock().unwrap().rate();
+    let config = best_config(&device, rate).map_err(|e| -> Box<dyn Error> {
+        let name = device
+            .name()
+            .unwrap_or_else(|_| "output device".to_string());
+        match describe_configs(&device) {
+            Ok(configs) => format!(
+                "{} has no f32, i16 or u16 config; it supports: {}",
+                name,
+                configs.join(", "),
+            )
+            .into(),
+            Err(_) => format!("{}: {}", name, e).into(),
+        }
+    })?;
+    eprintln!("config {:?} {:#?}", config.1, config.0);
+    mixer.lock().unwrap().set_rate(config.0.sample_rate.0);
+
+    // Set up the stream.
+    let stream = match config.1 {
+        cpal::SampleFormat::F32 => build_stream::<f32, N>(&device, &config.0, mixer)?,
+        cpal::SampleFormat::I16 => build_stream::<i16, N>(&device, &config.0, mixer)?,
+        cpal::SampleFormat::U16 => build_stream::<u16, N>(&device, &config.0, mixer)?,
+    };
+    stream.play()?;
 
+    eprintln!("stream built");
+    Ok(Player(stream))
+}
+
+// Sample rate of a config nearest the wanted rate.
+fn config_rate(config: &cpal::SupportedStreamConfigRange, rate: u32) -> u32 {
+    rate.clamp(config.min_sample_rate().0, config.max_sample_rate().0)
+}
+
+// Preference for a config, lower being better, or `None`
+// if it is unusable. Getting the wanted sample rate comes
+// first, then native float; after that, fewer channels
+// mean less copying.
+fn rank_config(config: &cpal::SupportedStreamConfigRange, rate: u32) -> Option<(u32, u8, u16)> {
+    if config.channels() == 0 {
+        return None;
+    }
+    let format = match config.sample_format() {
+        cpal::SampleFormat::F32 => 0,
+        cpal::SampleFormat::I16 => 1,
+        cpal::SampleFormat::U16 => 2,
+    };
+    let miss = config_rate(config, rate).abs_diff(rate);
+    Some((miss, format, config.channels()))
+}
+
+// Best supported config for the device, as near the wanted
+// sample rate as can be had.
+fn best_config(
+    device: &cpal::Device,
+    rate: u32,
+) -> Result<(cpal::StreamConfig, cpal::SampleFormat), cpal::SupportedStreamConfigsError> {
+    let config_range = device
+        .supported_output_configs()?
+        .filter_map(|c| Some((rank_config(&c, rate)?, c)))
+        .min_by_key(|(rank, _)| *rank)
+        .map(|(_, c)| c)
+        .ok_or(cpal::SupportedStreamConfigsError::DeviceNotAvailable)?;
+    let buffer_size = match config_range.buffer_size() {
+        cpal::SupportedBufferSize::Range { min, max } => {
+            eprintln!("buffer size {}..{}", min, max);
+            cpal::BufferSize::Fixed((*min).max(WANT_BUFSIZE.min(*max)))
+        }
+        cpal::SupportedBufferSize::Unknown => cpal::BufferSize::Default,
+    };
+    let config = cpal::StreamConfig {
+        channels: config_range.channels(),
+        sample_rate: cpal::SampleRate(config_rate(&config_range, rate)),
+        buffer_size,
+    };
+    Ok((config, config_range.sample_format()))
+}
+
+// Fill interleaved output frames from the mono samples,
+// copying each sample to every channel.
+fn fill_frames<T, I>(out: &mut [T], channels: usize, samples: &mut I)
+where
+    T: cpal::Sample,
+    I: Iterator<Item = f32>,
+{
+    for frame in out.chunks_mut(channels) {
+        // XXX Handle takedown somehow.
+        let s = samples.next().unwrap_or(0.0);
+        frame.fill(T::from(&s));
+    }
+}
+
+// Build an output stream with the given sample type.
+fn build_stream<T, N>(
+    device: &cpal::Device,
+    config: &cpal::StreamConfig,
+    mixer: Arc<Mutex<Mixer<N>>>,
+) -> Result<cpal::Stream, Box<dyn Error>>
+where
+    T: cpal::Sample,
+    N: Iterator<Item = f32> + Send + 'static,
+{
     // Build player callback.
-    let data_callback = move |out: &mut [i16], _info: &cpal::OutputCallbackInfo| {
+    let channels = usize::from(config.channels);
+    let data_callback = move |out: &mut [T], _info: &cpal::OutputCallbackInfo| {
         let mut samples = mixer.lock().unwrap();
-        let nout = out.len();
-        // println!("run {}", nout);
-        for i in 0..nout {
-            match samples.next() {
-                Some(s) => {
-                    out[i] = f32::floor(s * 32767.0) as i16;
-                }
-                None => {
-                    #[allow(clippy::needless_range_loop)]
-                    for j in i..nout {
-                        out[j] = 0;
-                    }
-                    // XXX Handle takedown somehow.
-                    break;
-                }
-            }
-        }
+        fill_frames(out, channels, &mut *samples);
     };
-    let data_callback = Box::leak(Box::new(data_callback));
 
     // Build player error callback.
     let error_callback = |err| {
@@ -92,10 +240,24 @@ where
         std::process::exit(1);
     };
 
-    // Set up the stream.
-    let stream = device.build_output_stream(&config, data_callback, error_callback)?;
-    stream.play()?;
+    Ok(device.build_output_stream(config, data_callback, error_callback)?)
+}
 
-    eprintln!("stream built");
-    Ok(Player(stream))
+#[test]
+// Check that mono samples are converted and copied to every
+// channel.
+fn test_fill_frames() {
+    let mut samples = [0.5, -1.0, 0.0].into_iter();
+    let mut out = [0i16; 8];
+    fill_frames(&mut out, 2, &mut samples);
+    assert_eq!(out[0], out[1]);
+    assert!(out[0] > 16000);
+    assert_eq!(out[2], out[3]);
+    assert!(out[2] < -32000);
+    assert_eq!([0; 4], out[4..]);
+
+    let mut samples = [1.0].into_iter();
+    let mut out = [0u16; 3];
+    fill_frames(&mut out, 3, &mut samples);
+    assert!(out.iter().all(|&s| s == u16::MAX));
 }
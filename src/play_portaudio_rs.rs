@@ -12,13 +12,138 @@ use portaudio_rs as pa;
 
 use crate::*;
 
-/// Gather samples and post for playback.
+// Name of a device's host API.
+fn host_name(info: &pa::device::DeviceInfo) -> String {
+    pa::hostapi::get_info(info.host_api)
+        .map(|h| h.name)
+        .unwrap_or_default()
+}
+
+// Describe a device's output capabilities.
+fn describe(info: &pa::device::DeviceInfo) -> String {
+    format!(
+        "{} ch, default {} Hz",
+        info.max_output_channels, info.default_sample_rate
+    )
+}
+
+/// An output device: index within its host API, global
+/// index and info.
+type OutputDevice = (usize, pa::device::DeviceIndex, pa::device::DeviceInfo);
+
+// Output devices, with their indexes within their hosts.
+fn output_devices() -> Result<Vec<OutputDevice>, Box<dyn Error>> {
+    pa::initialize()?;
+    let mut devices: Vec<OutputDevice> = Vec::new();
+    for index in 0..pa::device::get_count()? {
+        let info = match pa::device::get_info(index) {
+            Some(info) if info.max_output_channels > 0 => info,
+            _ => continue,
+        };
+        let i = devices
+            .iter()
+            .filter(|(_, _, d)| d.host_api == info.host_api)
+            .count();
+        devices.push((i, index, info));
+    }
+    Ok(devices)
+}
+
+/// List the output devices of every host API, with their
+/// capabilities.
+pub fn list_devices() -> Result<Vec<String>, Box<dyn Error>> {
+    let default = pa::device::get_default_output_index();
+    let mut lines = Vec::new();
+    let mut host = None;
+    for (i, index, info) in output_devices()? {
+        if host != Some(info.host_api) {
+            host = Some(info.host_api);
+            lines.push(format!("{}:", host_name(&info)));
+        }
+        let mark = if Some(index) == default {
+            " (default)"
+        } else {
+            ""
+        };
+        lines.push(format!("  {}: {}{}", i, info.name, mark));
+        lines.push(format!("      {}", describe(&info)));
+    }
+    Ok(lines)
+}
+
+/// Find an output device by `[host:]name` or `[host:]index`,
+/// as shown by [list_devices]. With no host the default host
+/// API is used.
+fn find_device(
+    spec: &str,
+) -> Result<(pa::device::DeviceIndex, pa::device::DeviceInfo), Box<dyn Error>> {
+    let devices = output_devices()?;
+    let default_host = pa::hostapi::get_default_index()?;
+    let host_named = |name: &str| {
+        devices
+            .iter()
+            .find(|(_, _, d)| host_name(d).eq_ignore_ascii_case(name))
+            .map(|(_, _, d)| d.host_api)
+    };
+    let (host, name) = match spec.split_once(':').map(|(h, n)| (host_named(h), n)) {
+        Some((Some(host), name)) => (host, name),
+        // Device names may contain colons.
+        _ => (default_host, spec),
+    };
+    let index = name.parse::<usize>().ok();
+    devices
+        .into_iter()
+        .filter(|(_, _, d)| d.host_api == host)
+        .find(|(i, _, d)| Some(*i) == index || d.name == name)
+        .map(|(_, index, info)| (index, info))
+        .ok_or_else(|| format!("no output device {}: try --list-devices", spec).into())
+}
+
+/// Gather samples and post for playback on the named device,
+/// or on the default device. The mixer's sample rate is
+/// used if the device supports it; otherwise the mixer is
+/// switched to the device's default rate.
 pub fn play<N>(
     mixer: Arc<Mutex<Mixer<N>>>,
+    device: Option<&str>,
 ) -> Result<Player<pa::stream::Stream<'static, f32, f32>>, Box<dyn Error>>
 where
     N: Iterator<Item = f32> + Send + 'static,
 {
+    // Get the device.
+    pa::initialize()?;
+    let (index, info) = match device {
+        Some(spec) => find_device(spec)?,
+        None => {
+            let index = pa::device::get_default_output_index()
+                .ok_or("no default output device: try --list-devices")?;
+            let info = pa::device::get_info(index).ok_or("default output device vanished")?;
+            (index, info)
+        }
+    };
+    let params = pa::stream::StreamParameters {
+        device: index,
+        channel_count: 1,
+        suggested_latency: info.default_low_output_latency,
+        data: 0.0f32,
+    };
+    // Use the mixer's rate if possible, else the device's.
+    let wanted = mixer.lock().unwrap().rate();
+    let supported =
+        |rate: u32| pa::stream::is_format_supported::<f32, f32>(None, Some(params), rate as f64);
+    let rate = [wanted, info.default_sample_rate as u32]
+        .into_iter()
+        .find(|&rate| supported(rate).is_ok())
+        .ok_or_else(|| {
+            format!(
+                "{} cannot play 1 ch f32 at {} Hz; it supports {}",
+                info.name,
+                wanted,
+                describe(&info)
+            )
+        })?;
+    mixer.lock().unwrap().set_rate(rate);
+
     let callback = move |_: &[f32], out: &mut [f32], _, _| {
         let mut samples = mixer.lock().unwrap();
         let mut result = pa::stream::StreamCallbackResult::Continue;
@@ -38,13 +163,13 @@ where
         result
     };
 
-    // Create and initialize audio output.
-    pa::initialize()?;
-    let stream = pa::stream::Stream::open_default(
-        0, // 0 input channels.
-        1, // 1 output channel.
-        SAMPLE_RATE as f64,
+    // Create audio output.
+    let stream = pa::stream::Stream::open(
+        None, // No input.
+        Some(params),
+        rate as f64,
         WANT_BUFSIZE as u64,
+        pa::stream::StreamFlags::empty(),
         Some(Box::new(callback)),
     )?;
     stream.start()?;
@@ -0,0 +1,234 @@
+// Copyright © 2022 Bart Massey
+// [This program is licensed under the "MIT License"]
+// Please see the file LICENSE in the source
+// distribution of this software for license terms.
+
+//! Offline rendering.
+//!
+//! Instead of playing the mixer live, pull its samples as
+//! fast as they can be computed and write them to a WAV
+//! file. Key events come from a timed list rather than a
+//! keyboard, so the same input always gives the same file.
+
+use std::convert::TryFrom;
+use std::error::Error;
+use std::io::{Seek, Write};
+
+use wmidi::{Channel, MidiMessage, U7};
+
+use crate::*;
+
+/// Longest time to keep rendering after the last event
+/// while waiting for notes to finish, in seconds.
+const MAX_TAIL: f32 = 60.0;
+
+/// A key event at a given time.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Event {
+    /// Time from the start of the render in seconds.
+    pub time: f32,
+    /// The event.
+    pub message: MidiMessage<'static>,
+}
+
+/// Parse a script of key events, one per line:
+///
+/// ```text
+/// # seconds  on  key velocity
+/// 0.0 on 60 100
+/// 0.5 off 60
+/// ```
+///
+/// Keys and velocities are MIDI numbers. Blank lines and
+/// `#` comments are ignored. The events are returned in
+/// time order.
+pub fn parse_script(script: &str) -> Result<Vec<Event>, Box<dyn Error>> {
+    let mut events = Vec::new();
+    for (lineno, line) in script.lines().enumerate() {
+        let line = line.split('#').next().unwrap();
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.is_empty() {
+            continue;
+        }
+        let bad = |what: &str| format!("script line {}: {}", lineno + 1, what);
+        let time: f32 = fields[0].parse().map_err(|_| bad("bad time"))?;
+        if !time.is_finite() || time < 0.0 {
+            return Err(bad("time out of range").into());
+        }
+        let number = |i: usize, what: &str| -> Result<U7, String> {
+            let n: u8 = fields
+                .get(i)
+                .ok_or_else(|| bad(&format!("missing {}", what)))?
+                .parse()
+                .map_err(|_| bad(&format!("bad {}", what)))?;
+            U7::try_from(n).map_err(|_| bad(&format!("{} out of range", what)))
+        };
+        let key = wmidi::Note::from(number(2, "key")?);
+        let message = match (fields.get(1), fields.len()) {
+            (Some(&"on"), 4) => {
+                let velocity = number(3, "velocity")?;
+                if u8::from(velocity) == 0 {
+                    MidiMessage::NoteOff(Channel::Ch1, key, velocity)
+                } else {
+                    MidiMessage::NoteOn(Channel::Ch1, key, velocity)
+                }
+            }
+            (Some(&"off"), 3) => MidiMessage::NoteOff(Channel::Ch1, key, U7::MIN),
+            _ => return Err(bad("expected `on key velocity` or `off key`").into()),
+        };
+        events.push(Event { time, message });
+    }
+    // Stable, so simultaneous events keep script order.
+    events.sort_by(|a, b| a.time.total_cmp(&b.time));
+    Ok(events)
+}
+
+/// Read a script of key events from a file. See
+/// [parse_script] for the format.
+pub fn read_script<P>(name: P) -> Result<Vec<Event>, Box<dyn Error>>
+where
+    P: AsRef<std::path::Path>,
+{
+    parse_script(&std::fs::read_to_string(name)?)
+}
+
+/// Render the mixer output for the given events, in time
+/// order, to a WAV file, passing each event to `handler` when its time
+/// comes. Rendering continues after the last event until the
+/// mixer falls silent. Returns the number of samples
+/// written.
+pub fn render<N, P, F>(
+    mixer: &mut Mixer<N>,
+    events: &[Event],
+    name: P,
+    handler: F,
+) -> Result<usize, Box<dyn Error>>
+where
+    N: Iterator<Item = f32>,
+    P: AsRef<std::path::Path>,
+    F: FnMut(&mut Mixer<N>, &MidiMessage<'static>),
+{
+    let writer = std::io::BufWriter::new(std::fs::File::create(name)?);
+    render_to(mixer, events, writer, handler)
+}
+
+/// Render as with [render], but to any seekable writer.
+pub fn render_to<N, W, F>(
+    mixer: &mut Mixer<N>,
+    events: &[Event],
+    writer: W,
+    mut handler: F,
+) -> Result<usize, Box<dyn Error>>
+where
+    N: Iterator<Item = f32>,
+    W: Write + Seek,
+    F: FnMut(&mut Mixer<N>, &MidiMessage<'static>),
+{
+    let spec = hound::WavSpec {
+        channels: 1,
+        sample_rate: mixer.rate(),
+        bits_per_sample: 16,
+        sample_format: hound::SampleFormat::Int,
+    };
+    let mut wav = hound::WavWriter::new(writer, spec)?;
+
+    let rate = mixer.rate() as f32;
+    let to_samples = |t: f32| (t * rate).round() as usize;
+    let end = events.last().map(|e| to_samples(e.time)).unwrap_or(0);
+    let mut pending = events.iter().peekable();
+    let mut n = 0;
+    let mut silent = 0;
+    loop {
+        while let Some(event) = pending.next_if(|e| to_samples(e.time) <= n) {
+            handler(mixer, &event.message);
+        }
+        if pending.peek().is_none() {
+            // Play out the notes, then the limiter delay.
+            if mixer.is_empty() {
+                silent += 1;
+            }
+            if silent > mixer.latency() || n >= end + to_samples(MAX_TAIL) {
+                break;
+            }
+        }
+        let s = mixer.next().unwrap();
+        wav.write_sample(f32::floor(s * 32767.0) as i16)?;
+        n += 1;
+    }
+    wav.finalize()?;
+    Ok(n)
+}
+
+#[test]
+// Check script parsing, including errors.
+fn test_parse_script() {
+    let events = parse_script("# test\n0.5 off 60\n\n0 on 60 100 # start\n0.5 on 62 0\n").unwrap();
+    let key = wmidi::Note::C4;
+    assert_eq!(3, events.len());
+    assert_eq!(
+        MidiMessage::NoteOn(Channel::Ch1, key, U7::try_from(100).unwrap()),
+        events[0].message
+    );
+    assert_eq!(0.5, events[1].time);
+    assert_eq!(
+        MidiMessage::NoteOff(Channel::Ch1, key, U7::MIN),
+        events[1].message
+    );
+    assert!(matches!(events[2].message, MidiMessage::NoteOff(..)));
+
+    for bad in [
+        "x on 60 1",
+        "-1 on 60 1",
+        "0 on 128 1",
+        "0 on 60",
+        "0 up 60",
+    ] {
+        assert!(parse_script(bad).is_err(), "{}", bad);
+    }
+}
+
+#[test]
+// Check that rendering is deterministic, plays out the
+// release, and runs at the mixer's sample rate.
+fn test_render() {
+    let gen = WaveGen::new(WaveShape::Saw);
+    let env = Breakpoints::adsr(0.01, 0.01, 0.5, 0.1);
+    let velocity = Velocity::default();
+    let events = parse_script("0 on 60 100\n0.1 on 64 80\n0.2 off 60\n0.2 off 64\n").unwrap();
+    let wav = |rate| {
+        let mut out = std::io::Cursor::new(Vec::new());
+        let mut mixer = Mixer::default().with_rate(rate);
+        let n = render_to(
+            &mut mixer,
+            &events,
+            &mut out,
+            |mixer, message| match *message {
+                MidiMessage::NoteOn(_, key, vel) => {
+                    let (freq, vel) = (key.to_freq_f32(), u8::from(vel));
+                    let note = Note::new(&gen, &env, freq, vel, &velocity, mixer.rate());
+                    mixer.add_key(u8::from(key) as usize, note);
+                }
+                MidiMessage::NoteOff(_, key, _) => mixer.release_key(u8::from(key) as usize),
+                _ => (),
+            },
+        )
+        .unwrap();
+        (n, out.into_inner())
+    };
+    let (_, a) = wav(DEFAULT_SAMPLE_RATE);
+    let (_, b) = wav(DEFAULT_SAMPLE_RATE);
+    assert_eq!(a, b);
+
+    for rate in [44_100, DEFAULT_SAMPLE_RATE] {
+        let (n, wav) = wav(rate);
+        // Events plus release, but not much more.
+        let expected = 0.3 * rate as f32;
+        assert!(n as f32 > expected && (n as f32) < 1.1 * expected, "{}", n);
+
+        let reader = hound::WavReader::new(&wav[..]).unwrap();
+        assert_eq!(rate, reader.spec().sample_rate);
+        let samples: Vec<i16> = reader.into_samples().map(Result::unwrap).collect();
+        assert_eq!(n, samples.len());
+        assert!(samples.iter().any(|&s| s.abs() > 1000));
+    }
+}
@@ -0,0 +1,298 @@
+// Copyright © 2022 Bart Massey
+// [This program is licensed under the "MIT License"]
+// Please see the file LICENSE in the source
+// distribution of this software for license terms.
+
+//! Table-driven resampling.
+//!
+//! Reading a sample at a fractional position means filtering
+//! the samples around it. Rather than compute the filter
+//! afresh for every output sample, a windowed-sinc
+//! [Resampler] tabulates it once for a range of fractional
+//! offsets ("phases") and interpolates between neighboring
+//! phases. Cheaper polynomial interpolation is also offered
+//! where quality matters less than speed.
+
+use std::f32::consts::PI;
+use std::str::FromStr;
+
+#[cfg(test)]
+use crate::{resamp, Rng};
+
+/// Most taps a windowed-sinc filter may have.
+pub const MAX_TAPS: usize = 64;
+
+// Number of tabulated fractional offsets between samples.
+const PHASES: usize = 256;
+
+/// How to interpolate between samples.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Quality {
+    /// Straight line between neighboring samples.
+    Linear,
+    /// Catmull-Rom cubic through four samples.
+    Cubic,
+    /// Kaiser-windowed sinc lowpass. More taps and a higher
+    /// `beta` (window shape) give a sharper filter with less
+    /// leakage, at more cost.
+    Sinc { taps: usize, beta: f32 },
+}
+
+impl Default for Quality {
+    /// A 16-tap sinc: clean enough for playback, and cheap.
+    fn default() -> Self {
+        Quality::Sinc {
+            taps: 16,
+            beta: 8.0,
+        }
+    }
+}
+
+impl FromStr for Quality {
+    type Err = String;
+
+    /// Parse `linear`, `cubic`, or `sinc` with optional tap
+    /// count and window beta, as in `sinc:32:9.5`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || {
+            format!(
+                "invalid resampling quality {}: use linear, cubic or sinc[:taps[:beta]]",
+                s
+            )
+        };
+        let mut fields = s.split(':');
+        let name = fields.next().unwrap_or_default();
+        let params: Vec<&str> = fields.collect();
+        match (name, params.as_slice()) {
+            ("lin" | "linear", []) => Ok(Quality::Linear),
+            ("cubic", []) => Ok(Quality::Cubic),
+            ("sinc", params) if params.len() <= 2 => {
+                let (mut taps, mut beta) = (16, 8.0);
+                if let Some(t) = params.first() {
+                    taps = t.parse().map_err(|_| err())?;
+                }
+                if let Some(b) = params.get(1) {
+                    beta = b.parse().map_err(|_| err())?;
+                }
+                if taps % 2 != 0 || !(2..=MAX_TAPS).contains(&taps) {
+                    return Err(err());
+                }
+                Ok(Quality::Sinc { taps, beta })
+            }
+            _ => Err(err()),
+        }
+    }
+}
+
+// Zeroth-order modified Bessel function of the first kind,
+// by its power series.
+fn bessel_i0(x: f32) -> f32 {
+    let mut sum = 1.0;
+    let mut term = 1.0;
+    let half = 0.5 * x;
+    for k in 1..50 {
+        term *= half / k as f32;
+        let t = term * term;
+        sum += t;
+        if t < 1.0e-9 * sum {
+            break;
+        }
+    }
+    sum
+}
+
+// Kaiser window at `u` in -1..=1, given the Bessel
+// function of `beta` for normalization.
+fn kaiser(u: f32, beta: f32, i0_beta: f32) -> f32 {
+    let r = 1.0 - u * u;
+    if r <= 0.0 {
+        return 0.0;
+    }
+    bessel_i0(beta * r.sqrt()) / i0_beta
+}
+
+// Sample `i` of the buffer, or silence outside it.
+fn tap(buf: &[f32], i: i64) -> f32 {
+    if i >= 0 && (i as usize) < buf.len() {
+        buf[i as usize]
+    } else {
+        0.0
+    }
+}
+
+/// Interpolator reading a buffer at fractional positions.
+#[derive(Debug, Clone)]
+pub struct Resampler {
+    quality: Quality,
+    /// Number of filter taps.
+    taps: usize,
+    /// Filter coefficients, `taps` for each of `PHASES + 1`
+    /// fractional offsets from 0 to 1 inclusive.
+    table: Vec<f32>,
+}
+
+impl Resampler {
+    /// Make a resampler for a buffer sampled at `fsr` Hz,
+    /// passing frequencies below `fmax` Hz. Only the sinc
+    /// filter honors the cutoff.
+    pub fn new(quality: Quality, fmax: f32, fsr: f32) -> Self {
+        let taps = match quality {
+            Quality::Linear => 2,
+            Quality::Cubic => 4,
+            Quality::Sinc { taps, .. } => taps,
+        };
+        assert!(
+            taps % 2 == 0 && (2..=MAX_TAPS).contains(&taps),
+            "bad resampler tap count {}",
+            taps
+        );
+        let table = match quality {
+            Quality::Sinc { beta, .. } => {
+                let fc = fmax / fsr;
+                let half = (taps / 2) as f32;
+                let i0_beta = bessel_i0(beta);
+                let mut table = Vec::with_capacity(taps * (PHASES + 1));
+                for p in 0..=PHASES {
+                    let frac = p as f32 / PHASES as f32;
+                    let row: Vec<f32> = (0..taps)
+                        .map(|k| {
+                            let t = k as f32 + 1.0 - half - frac;
+                            let a = 2.0 * PI * fc * t;
+                            let sinc = if a == 0.0 { 1.0 } else { f32::sin(a) / a };
+                            2.0 * fc * sinc * kaiser(t / half, beta, i0_beta)
+                        })
+                        .collect();
+                    // Pass DC at exactly unity gain.
+                    let gain: f32 = row.iter().sum();
+                    table.extend(row.iter().map(|c| c / gain));
+                }
+                table
+            }
+            _ => Vec::new(),
+        };
+        Self {
+            quality,
+            taps,
+            table,
+        }
+    }
+
+    /// Quality of this resampler.
+    pub fn quality(&self) -> Quality {
+        self.quality
+    }
+
+    /// Samples needed past a position to read it.
+    pub fn lookahead(&self) -> usize {
+        self.taps / 2
+    }
+
+    /// Read the buffer at position `x`, in samples. The
+    /// buffer is taken to be silent outside its bounds.
+    pub fn at(&self, x: f32, buf: &[f32]) -> f32 {
+        let i = x.floor();
+        let frac = x - i;
+        let i = i as i64;
+        match self.quality {
+            Quality::Linear => {
+                let (a, b) = (tap(buf, i), tap(buf, i + 1));
+                a + frac * (b - a)
+            }
+            Quality::Cubic => {
+                let (y0, y1, y2, y3) = (
+                    tap(buf, i - 1),
+                    tap(buf, i),
+                    tap(buf, i + 1),
+                    tap(buf, i + 2),
+                );
+                let c1 = 0.5 * (y2 - y0);
+                let c2 = y0 - 2.5 * y1 + 2.0 * y2 - 0.5 * y3;
+                let c3 = 0.5 * (y3 - y0) + 1.5 * (y1 - y2);
+                ((c3 * frac + c2) * frac + c1) * frac + y1
+            }
+            Quality::Sinc { .. } => {
+                let pos = frac * PHASES as f32;
+                let p = usize::min(pos as usize, PHASES - 1);
+                let g = pos - p as f32;
+                let a = &self.table[p * self.taps..(p + 1) * self.taps];
+                let b = &self.table[(p + 1) * self.taps..(p + 2) * self.taps];
+                let first = i + 1 - self.lookahead() as i64;
+                let coeffs = a.iter().zip(b).map(|(a, b)| a + g * (b - a));
+                if first >= 0 && first as usize + self.taps <= buf.len() {
+                    let window = &buf[first as usize..first as usize + self.taps];
+                    coeffs.zip(window).map(|(c, s)| c * s).sum()
+                } else {
+                    coeffs.zip(first..).map(|(c, j)| c * tap(buf, j)).sum()
+                }
+            }
+        }
+    }
+}
+
+#[test]
+// Check quality parsing, including defaults and bad tap
+// counts.
+fn test_quality_parse() {
+    assert_eq!(Ok(Quality::Linear), "linear".parse());
+    assert_eq!(Ok(Quality::Cubic), "cubic".parse());
+    assert_eq!(Ok(Quality::default()), "sinc".parse());
+    assert_eq!(
+        Ok(Quality::Sinc {
+            taps: 32,
+            beta: 9.5
+        }),
+        "sinc:32:9.5".parse()
+    );
+    for bad in ["sinc:7", "sinc:128", "sinc:8:x", "cubicle", "sinc:8:8:8"] {
+        assert!(bad.parse::<Quality>().is_err(), "{}", bad);
+    }
+}
+
+#[test]
+// Check that the polynomial interpolators pass through the
+// samples and draw the expected curves between them.
+fn test_polynomial() {
+    let buf = [0.0, 1.0, 4.0, 9.0, 16.0];
+    for quality in [Quality::Linear, Quality::Cubic] {
+        let r = Resampler::new(quality, 0.0, 1.0);
+        for (i, &s) in buf.iter().enumerate() {
+            assert_eq!(s, r.at(i as f32, &buf));
+        }
+    }
+    assert_eq!(2.5, Resampler::new(Quality::Linear, 0.0, 1.0).at(1.5, &buf));
+    // Catmull-Rom reproduces a parabola exactly.
+    assert_eq!(6.25, Resampler::new(Quality::Cubic, 0.0, 1.0).at(2.5, &buf));
+}
+
+#[test]
+// Check that the tabulated sinc agrees with the reference
+// windowed sinc on a band-limited signal, and holds DC.
+fn test_sinc_agrees() {
+    let rate = 48_000.0;
+    let buf: Vec<f32> = (0..2000)
+        .map(|i| {
+            let t = 2.0 * PI * i as f32 / rate;
+            0.5 * f32::sin(440.0 * t)
+                + 0.3 * f32::sin(3_100.0 * t + 1.0)
+                + 0.2 * f32::sin(9_000.0 * t)
+        })
+        .collect();
+    let fmax = 20_000.0;
+    let sinc = Resampler::new(
+        Quality::Sinc {
+            taps: MAX_TAPS,
+            beta: 10.0,
+        },
+        fmax,
+        rate,
+    );
+    let mut rng = Rng::new(3);
+    for _ in 0..200 {
+        let x = 500.0 + 1000.0 * rng.uniform();
+        let reference = resamp(x, &buf, fmax, rate, 65);
+        let y = sinc.at(x, &buf);
+        assert!((y - reference).abs() < 0.01, "{}: {} {}", x, y, reference);
+    }
+    let r = Resampler::new(Quality::default(), fmax, rate);
+    assert!((r.at(100.37, &[0.5; 200]) - 0.5).abs() < 1.0e-5);
+}
@@ -7,54 +7,165 @@
 
 use std::f32::consts::PI;
 
-use dsp::{
-    node::{complex::RealToComplex, fft},
-    num_complex::Complex32,
-    runtime::node::ProcessNode,
-    spectrum,
-};
-
 use crate::*;
 
 // Width of resampling filter in samples. Should be odd,
 // since centered on target sample. Larger is better and
 // slower.
-const RESAMP_WIDTH: i64 = 9;
+pub(crate) const RESAMP_WIDTH: i64 = 9;
+
+// Filter used to convert whole samples between sample
+// rates. This is done once at load time, so can afford to
+// be much wider than for playback.
+const CONVERT_QUALITY: Quality = Quality::Sinc {
+    taps: MAX_TAPS,
+    beta: 10.0,
+};
+
+// Filter used to halve the sample rate of a loop for each
+// octave of transposition, with its cutoff as a fraction of
+// the original rate. The cutoff leaves room for the
+// transition band to fold back above the playback cutoff.
+const DECIMATE_QUALITY: Quality = Quality::Sinc {
+    taps: 32,
+    beta: 8.0,
+};
+const DECIMATE_CUTOFF: f32 = 0.225;
+
+// Playback filters tabulated per loop: within an octave the
+// cutoff is lowered in this many steps, each rounding the
+// transposition up so as not to alias.
+const CUTOFF_STEPS: usize = 4;
 
 // Minimum and maximum expected fundamental frequency of
 // samples in Hz.
 const F_MIN: f32 = 110.0;
 const F_MAX: f32 = 1720.0;
 
-// FFT Length. Ideally power of two.
-const NFFT: usize = 16_384;
+// Most samples compared per lag in pitch detection.
+const YIN_WINDOW: usize = 2048;
+
+// Normalized difference below which a lag is taken as the
+// period.
+const YIN_THRESHOLD: f32 = 0.15;
+
+// Least pitch detection confidence to believe.
+const MIN_CONFIDENCE: f32 = 0.8;
+
+/// Estimated fundamental frequency of a sample.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Pitch {
+    /// Frequency in Hz.
+    pub freq: f32,
+    /// How periodic the sample is at that frequency, from 0
+    /// (not at all) to 1 (exactly).
+    pub confidence: f32,
+}
+
+/// Estimate the fundamental frequency of the buffer, sampled
+/// at the given rate, between `F_MIN` and `F_MAX`. This is
+/// the YIN method: find the shortest lag at which the
+/// signal nearly repeats. Returns `None` if the buffer is
+/// too short or silent.
+pub fn detect_pitch(buf: &[f32], rate: u32) -> Option<Pitch> {
+    let tau_min = (rate as f32 / F_MAX).floor() as usize;
+    let tau_max = usize::min((rate as f32 / F_MIN).ceil() as usize, buf.len() / 2);
+    if tau_max < tau_min + 2 {
+        return None;
+    }
+    // Compare from the middle of the sample, clear of the
+    // attack.
+    let window = usize::min(YIN_WINDOW, buf.len() - tau_max);
+    let offset = (buf.len() - window - tau_max) / 2;
+    let x = &buf[offset..];
+
+    // Cumulative mean normalized difference.
+    let mut d = vec![1.0; tau_max + 1];
+    let mut total = 0.0;
+    for tau in 1..=tau_max {
+        let diff: f32 = (0..window).map(|j| (x[j] - x[j + tau]).powi(2)).sum();
+        total += diff;
+        d[tau] = if total > 0.0 {
+            diff * tau as f32 / total
+        } else {
+            1.0
+        };
+    }
+    if total == 0.0 {
+        return None;
+    }
 
-// Find the maximum frequency of the buffer.
-fn max_freq(buf: &[f32]) -> f32 {
-    let mut tc = RealToComplex::new();
-    let mut csignal = vec![Complex32::default(); NFFT];
-    tc.process_buffer(&buf[..NFFT], &mut csignal).unwrap();
+    // The first dip under the threshold, at its bottom, or
+    // failing that the deepest dip.
+    let tau = match (tau_min..tau_max).find(|&tau| d[tau] < YIN_THRESHOLD) {
+        Some(mut tau) => {
+            while tau + 1 < tau_max && d[tau + 1] < d[tau] {
+                tau += 1;
+            }
+            tau
+        }
+        None => (tau_min..tau_max)
+            .min_by(|&a, &b| d[a].total_cmp(&d[b]))
+            .unwrap(),
+    };
 
-    // Do the FFT and return the frequency with maximum amplitude.
-    let mut ft = fft::ForwardFFT::new(NFFT, fft::WindowType::Hamming);
-    let mut spectrum = vec![Complex32::default(); NFFT];
-    ft.process_buffer(&csignal, &mut spectrum).unwrap();
-    spectrum::max_freq(&spectrum, SAMPLE_RATE as usize)
+    // Fit a parabola through the dip for a fractional lag.
+    let (a, b, c) = (d[tau - 1], d[tau], d[tau + 1]);
+    let curve = a - 2.0 * b + c;
+    let shift = if curve > 0.0 {
+        (0.5 * (a - c) / curve).clamp(-0.5, 0.5)
+    } else {
+        0.0
+    };
+    Some(Pitch {
+        freq: rate as f32 / (tau as f32 + shift),
+        confidence: (1.0 - b).clamp(0.0, 1.0),
+    })
 }
 
 #[test]
-// Check that a DC signal has a DC maximum.
-fn test_max_freq_0() {
-    assert_eq!(0.0, max_freq(&[1.0; NFFT]));
+// Check that a pure tone is found accurately, even in a
+// short buffer and at other sample rates.
+fn test_detect_pitch_tone() {
+    for (freq, len, rate) in [
+        (440.0, 48_000, DEFAULT_SAMPLE_RATE),
+        (220.0, 2_000, DEFAULT_SAMPLE_RATE),
+        (1000.0, 4_000, 44_100),
+    ] {
+        let buf: Vec<f32> = (0..len)
+            .map(|i| f32::sin(2.0 * PI * freq * i as f32 / rate as f32))
+            .collect();
+        let pitch = detect_pitch(&buf, rate).unwrap();
+        assert!((pitch.freq - freq).abs() < 0.005 * freq, "{:?}", pitch);
+        assert!(pitch.confidence > 0.95, "{:?}", pitch);
+    }
 }
 
 #[test]
-// Check that a maximum-frequency signal is correct.
-fn test_max_freq_nyquist() {
-    let buf: Vec<f32> = (0..NFFT)
-        .map(|i| if i % 2 == 0 { -1.0 } else { 1.0 })
+// Check that a weak fundamental under strong harmonics is
+// still found.
+fn test_detect_pitch_harmonics() {
+    let f = 200.0;
+    let buf: Vec<f32> = (0..8_000)
+        .map(|i| {
+            let t = 2.0 * PI * f * i as f32 / DEFAULT_SAMPLE_RATE as f32;
+            0.2 * f32::sin(t) + f32::sin(2.0 * t) + 0.7 * f32::sin(3.0 * t)
+        })
         .collect();
-    assert_eq!(SAMPLE_RATE as f32 / 2.0, max_freq(&buf));
+    let pitch = detect_pitch(&buf, DEFAULT_SAMPLE_RATE).unwrap();
+    assert!((pitch.freq - f).abs() < 1.0, "{:?}", pitch);
+}
+
+#[test]
+// Check that silence, noise and tiny buffers give no
+// confident pitch.
+fn test_detect_pitch_none() {
+    assert_eq!(None, detect_pitch(&[0.0; 4_000], DEFAULT_SAMPLE_RATE));
+    assert_eq!(None, detect_pitch(&[0.5; 50], DEFAULT_SAMPLE_RATE));
+    let mut rng = Rng::new(1);
+    let noise: Vec<f32> = (0..8_000).map(|_| rng.uniform() - 0.5).collect();
+    let pitch = detect_pitch(&noise, DEFAULT_SAMPLE_RATE).unwrap();
+    assert!(pitch.confidence < MIN_CONFIDENCE, "{:?}", pitch);
 }
 
 // Plain old dot product.
@@ -68,16 +179,16 @@ fn test_dot() {
     assert_eq!(14.0, dot(&[1.0, 3.0], &[2.0, 4.0]));
 }
 
-// Given a buffer, a corr length, and a range of end
-// segments at lag..0, return the amount to clip off the end
-// of the buffer to get best circular correlation, and the
-// score of that clip.
-fn best_loop(buf: &[f32], len: usize, lag: usize) -> (f32, usize) {
+// Given a buffer, a loop start, a corr length, and a range
+// of end segments at lag..0, return the amount to clip off
+// the end of the buffer to get best circular correlation
+// back to the start, and the score of that clip.
+fn best_loop(buf: &[f32], start: usize, len: usize, lag: usize) -> (f32, usize) {
     let nbuf = buf.len();
     let mut cinfo: Option<(f32, usize)> = None;
     for t in (0..lag).rev() {
         let u = nbuf - len - t;
-        let corr = dot(&buf[..len], &buf[u..u + len]);
+        let corr = dot(&buf[start..start + len], &buf[u..u + len]);
         if cinfo.is_none() || corr > cinfo.unwrap().0 {
             cinfo = Some((corr, t))
         }
@@ -89,40 +200,207 @@ fn best_loop(buf: &[f32], len: usize, lag: usize) -> (f32, usize) {
 // Test that the end sample is clipped off a short thing.
 fn test_best_loop() {
     let samples = [1.0, 1.0, 1.0, 1.0, 1.0, 1.0, 0.0];
-    assert_eq!(1, best_loop(&samples, 2, 2).1);
+    assert_eq!(1, best_loop(&samples, 0, 2, 2).1);
+}
+
+// Find the end of the attack of a sample: the loudest
+// point in the first half of the buffer. The sustain loop
+// starts here. Returns 0 if the loop would not leave room
+// for `room` samples after the start.
+fn attack_end(buf: &[f32], room: usize) -> usize {
+    let end = buf[..buf.len() / 2]
+        .iter()
+        .enumerate()
+        .fold(
+            (0, 0.0),
+            |(i, m), (j, &s)| {
+                if s.abs() > m {
+                    (j, s.abs())
+                } else {
+                    (i, m)
+                }
+            },
+        )
+        .0;
+    if end + room <= buf.len() {
+        end
+    } else {
+        0
+    }
+}
+
+// Find the first rising zero crossing in `from..to`, as a
+// fractional sample index, or `from` if there is none.
+fn zero_crossing(buf: &[f32], from: usize, to: usize) -> f32 {
+    (from + 1..to.min(buf.len()))
+        .find(|&i| buf[i - 1] <= 0.0 && buf[i] > 0.0)
+        .map(|i| {
+            let (a, b) = (buf[i - 1], buf[i]);
+            (i - 1) as f32 + a / (a - b)
+        })
+        .unwrap_or(from as f32)
+}
+
+#[test]
+// Check that zero crossings are found between samples.
+fn test_zero_crossing() {
+    let buf = [0.5, -0.5, -0.25, 0.75, 1.0];
+    assert_eq!(2.25, zero_crossing(&buf, 0, 5));
+    assert_eq!(3.0, zero_crossing(&buf, 3, 5));
+    assert_eq!(0.0, zero_crossing(&[0.0, 1.0], 0, 2));
 }
 
-/// Iterator producing resampled audio samples.  This is an
-/// unbounded iterator.
+#[test]
+// Check that the attack ends at the peak.
+fn test_attack_end() {
+    let buf: Vec<f32> = (0..100)
+        .map(|i| f32::min(i as f32 / 20.0, 1.0 - i as f32 / 100.0))
+        .collect();
+    assert_eq!(17, attack_end(&buf, 10));
+    assert_eq!(0, attack_end(&buf, 90));
+}
+
+// Halve the sample rate of a buffer.
+fn decimate(buf: &[f32]) -> Vec<f32> {
+    let resampler = Resampler::new(DECIMATE_QUALITY, DECIMATE_CUTOFF, 1.0);
+    (0..buf.len().div_ceil(2))
+        .map(|i| resampler.at(2.0 * i as f32, buf))
+        .collect()
+}
+
+// Number of halvings that take a buffer down to a single
+// sample.
+fn octave_depth(len: usize) -> usize {
+    (usize::BITS - len.saturating_sub(1).leading_zeros()) as usize
+}
+
+// Copies of the buffer at successively halved sample rates,
+// as many as asked for.
+fn octaves(buf: &[f32], depth: usize) -> Vec<Vec<f32>> {
+    let mut octaves: Vec<Vec<f32>> = Vec::with_capacity(depth);
+    while octaves.len() < depth {
+        let next = decimate(octaves.last().map_or(buf, Vec::as_slice));
+        octaves.push(next);
+    }
+    octaves
+}
+
+#[test]
+// Check that decimation keeps low tones, removes tones it
+// cannot represent, and halves down to one sample.
+fn test_octaves() {
+    let tone = |freq: f32| -> Vec<f32> {
+        (0..1000)
+            .map(|i| f32::sin(2.0 * PI * freq * i as f32))
+            .collect()
+    };
+    let rms = |buf: &[f32]| f32::sqrt(buf[100..400].iter().map(|s| s * s).sum::<f32>() / 300.0);
+    assert_eq!(10, octave_depth(1000));
+    assert_eq!(
+        (0, 0, 1, 2),
+        (
+            octave_depth(0),
+            octave_depth(1),
+            octave_depth(2),
+            octave_depth(3)
+        )
+    );
+    let low = octaves(&tone(0.05), 10);
+    assert_eq!(
+        vec![500, 250, 125, 63, 32, 16, 8, 4, 2, 1],
+        low.iter().map(Vec::len).collect::<Vec<_>>()
+    );
+    assert!((rms(&low[0]) - f32::sqrt(0.5)).abs() < 0.01);
+    let high = octaves(&tone(0.4), 1);
+    assert!(rms(&high[0]) < 0.01);
+}
+
+// Playback filters for a loop sampled at the given rate,
+// for transpositions of 1 up to 2 within an octave.
+fn playback_resamplers(quality: Quality, rate: u32) -> Vec<Resampler> {
+    let fmax = f32::min(20_000.0, 0.5 * rate as f32);
+    (0..=CUTOFF_STEPS)
+        .map(|i| {
+            let divisor = f32::powf(2.0, i as f32 / CUTOFF_STEPS as f32);
+            Resampler::new(quality, fmax / divisor, rate as f32)
+        })
+        .collect()
+}
+
+/// Iterator producing resampled audio samples. This is an
+/// unbounded iterator while the key is held: once released,
+/// it ends after playing out any release tail.
 #[derive(Debug, Clone)]
 pub struct Samples<'a> {
     buf: &'a [f32],
+    seam: &'a [f32],
+    start: f32,
+    end: f32,
+    fade: f32,
+    zero: f32,
+    looped: bool,
+    looping: bool,
+    /// There are samples after the loop to play on release.
+    tail: bool,
+    releasing: bool,
     incr: f32,
-    cutoff: f32,
+    /// Original samples per sample of the octave read from.
+    scale: f32,
+    resampler: &'a Resampler,
     x: f32,
 }
 
 impl<'a> Samples<'a> {
     // Make a new resampling iterator.
-    pub fn new(sloop: &'a Loop, incr: f32, cutoff: f32) -> Self {
-        assert!(incr.abs() < RESAMP_WIDTH as f32 / 2.0);
+    pub fn new(sloop: &'a Loop, incr: f32) -> Self {
+        // Read from the octave that takes less than two of
+        // its samples per step, so that the filter need not
+        // widen.
+        let mut octave = 0;
+        while incr.abs() >= 2.0 * (1u64 << octave) as f32 && octave < sloop.octaves.len() {
+            octave += 1;
+        }
+        let scale = (1u64 << octave) as f32;
+        // Lower the cutoff by the transposition left over,
+        // as the octave's rate is to the loop's.
+        let over = f32::max(1.0, incr.abs() / scale);
+        let step = (f32::log2(over) * CUTOFF_STEPS as f32).ceil() as usize;
+        let resampler = &sloop.resamplers[step.min(CUTOFF_STEPS)];
+        let (full, seam) = match octave {
+            0 => (&sloop.buf[..], sloop.seam.as_deref()),
+            o => (
+                &sloop.octaves[o - 1][..],
+                sloop.seam_octaves.get(o - 1).map(Vec::as_slice),
+            ),
+        };
+        let len = if sloop.tail || !sloop.looped {
+            full.len()
+        } else {
+            usize::min((sloop.end as f32 / scale).ceil() as usize, full.len())
+        };
+        let buf = &full[..len];
         Self {
-            buf: &sloop.buf,
+            buf,
+            seam: seam.unwrap_or(buf),
+            start: sloop.start as f32,
+            end: sloop.end as f32,
+            fade: sloop.fade as f32,
+            zero: sloop.zero,
+            looped: sloop.looped,
+            looping: sloop.looped,
+            tail: (sloop.tail || !sloop.looped) && sloop.buf.len() > sloop.end,
+            releasing: false,
             incr,
-            cutoff,
-            x: 0.0,
+            scale,
+            resampler,
+            x: sloop.zero,
         }
     }
 
     /// Reset the iterator to the beginning of the loop.
     /// This will ensure that it starts at a zero-crossing.
-    ///
-    /// # Bug
-    ///
-    /// The above assertion is not yet true: samples don't
-    /// necessarily start at zero crossings right now.
     pub fn reset(&mut self) {
-        self.x = 0.0;
+        self.x = self.zero;
     }
 }
 
@@ -131,62 +409,286 @@ impl<'a> Iterator for Samples<'a> {
 
     /// Return the next sample from the iterator.
     fn next(&mut self) -> Option<f32> {
-        let s = resamp(self.x, self.buf, self.cutoff, RESAMP_WIDTH);
-        let nbuf = self.buf.len() as f32;
+        // The crossfaded seam leads back to the loop start,
+        // not on to the tail: once released, wait until
+        // playback is clear of it to leave the loop.
+        if self.releasing && self.looping && self.x < self.fade {
+            self.looping = false;
+        }
+        let buf = if self.looping { self.seam } else { self.buf };
+        if self.x >= buf.len() as f32 * self.scale {
+            return None;
+        }
+        let s = self.resampler.at(self.x / self.scale, buf);
         self.x += self.incr;
-        while self.x >= nbuf {
-            self.x -= nbuf;
+        if self.looping {
+            while self.x >= self.end {
+                self.x -= self.end - self.start;
+            }
         }
         Some(s)
     }
 }
 
+impl Stream for Samples<'_> {
+    /// Leave the loop and play through the release tail, if
+    /// there is one. Without a tail, keep looping until the
+    /// note's envelope ends.
+    fn release(&mut self) {
+        if self.tail {
+            self.releasing = true;
+        }
+    }
+
+    /// Play the attack again.
+    fn retrigger(&mut self) {
+        self.looping = self.looped;
+        self.releasing = false;
+        self.reset();
+    }
+}
+
 /// An audio sample loop that has been frequency-analyzed
-/// and trimmed for looping.
+/// and trimmed for looping. Playback runs through the
+/// attack from the start of the buffer to the loop end,
+/// then goes back to the loop start for as long as the key
+/// is held. With a release tail, the rest of the buffer is
+/// played after the key is released.
+///
+/// The loop seam can be crossfaded: the end of the loop is
+/// faded into the samples leading up to the loop start, in
+/// a copy of the buffer used only while looping.
 #[derive(Debug)]
 pub struct Loop {
     buf: Vec<f32>,
+    seam: Option<Vec<f32>>,
     freq: Option<f32>,
+    rate: u32,
+    start: usize,
+    end: usize,
+    /// Start of the crossfade before the loop end.
+    fade: usize,
+    /// Playback start, at a zero crossing.
+    zero: f32,
+    looped: bool,
+    tail: bool,
+    /// Playback filters, for each step of transposition
+    /// within an octave.
+    resamplers: Vec<Resampler>,
+    /// The buffer at successively halved sample rates, for
+    /// playing far above the root.
+    octaves: Vec<Vec<f32>>,
+    /// The seam likewise.
+    seam_octaves: Vec<Vec<f32>>,
 }
 
 impl Loop {
-    /// Make a `Loop` out of some samples.
-    pub fn new(buf: &[f32]) -> Self {
-        // Find the dominant frequency.
-        let f_max = max_freq(buf);
-        let p = |f| f32::floor(SAMPLE_RATE as f32 / f + 0.5) as usize;
-        let (freq, p_max) = if (F_MIN..=F_MAX).contains(&f_max) {
-            (Some(f_max), p(f_max))
+    /// Make a `Loop` out of some samples recorded at the
+    /// given sample rate, finding the pitch and loop
+    /// point by analysis.
+    pub fn new(buf: &[f32], rate: u32) -> Self {
+        Self::from_sample(buf, rate, &SampleMeta::default())
+    }
+
+    /// Make a `Loop` out of some samples recorded at the
+    /// given sample rate. The root key and loop in the
+    /// metadata are used when present, falling back to
+    /// analysis when not.
+    pub fn from_sample(buf: &[f32], rate: u32, meta: &SampleMeta) -> Self {
+        // Find the fundamental frequency.
+        let p = |f| f32::floor(rate as f32 / f + 0.5) as usize;
+        let (freq, p_max) = if let Some(key) = meta.root {
+            let f = 440.0 * f32::powf(2.0, (key - 69.0) / 12.0);
+            (Some(f), p(f))
         } else {
-            (None, p(F_MAX))
+            // Treat the sample as unpitched if detection is
+            // doubtful.
+            match detect_pitch(buf, rate) {
+                Some(pitch) if pitch.confidence >= MIN_CONFIDENCE => {
+                    (Some(pitch.freq), p(pitch.freq))
+                }
+                _ => (None, p(F_MAX)),
+            }
         };
 
-        // Find the best place to close off the loop and do so.
-        let (_, t) = best_loop(buf, 2 * p_max, 2 * p_max);
-        let buf = &buf[0..buf.len() - t];
+        // Find the best place to close off the loop and do
+        // so, unless told where it is. The loop starts after
+        // the attack.
+        let (start, end) = match meta.sustain {
+            Some(ref sustain) if !sustain.is_empty() && sustain.end <= buf.len() => {
+                (sustain.start, sustain.end)
+            }
+            _ => {
+                let (len, lag) = (2 * p_max, 2 * p_max);
+                if buf.len() < 2 * len + lag {
+                    // Too short to search: loop it all.
+                    (0, buf.len())
+                } else {
+                    let start = attack_end(buf, 2 * len + lag);
+                    (start, buf.len() - best_loop(buf, start, len, lag).1)
+                }
+            }
+        };
+        let gain = f32::powf(10.0, meta.gain / 20.0);
+        let buf: Vec<f32> = buf.iter().map(|&s| gain * s).collect();
 
         // Return the loop for future sampling.
         Self {
-            buf: buf.to_owned(),
+            octaves: octaves(&buf, octave_depth(buf.len())),
+            seam_octaves: Vec::new(),
+            seam: None,
             freq,
+            rate,
+            start,
+            end,
+            fade: end,
+            zero: zero_crossing(&buf, 0, end),
+            looped: true,
+            tail: false,
+            resamplers: playback_resamplers(Quality::default(), rate),
+            buf,
         }
     }
 
+    /// Crossfade the loop seam over the given time in
+    /// seconds, with equal-power curves. The crossfade is
+    /// limited to half the loop. If there is not enough
+    /// before the loop start to fade in, the loop start is
+    /// moved later to make room.
+    pub fn with_crossfade(mut self, time: f32) -> Self {
+        let n = (time * self.rate as f32).round() as usize;
+        let n = n.min((self.end - self.start) / 2);
+        if n == 0 {
+            return self;
+        }
+        let from = self.start.saturating_sub(n);
+        self.start = from + n;
+        self.fade = self.end - n;
+
+        let mut seam = self.buf[..self.end].to_vec();
+        for i in 0..n {
+            let t = 0.5 * PI * (i as f32 + 0.5) / n as f32;
+            let (out, into) = (f32::cos(t), f32::sin(t));
+            seam[self.fade + i] = out * self.buf[self.fade + i] + into * self.buf[from + i];
+        }
+        // The resampling filter looks past the loop end, the
+        // further the lower the octave read from: show it
+        // the loop again.
+        seam.extend_from_slice(&self.buf[self.start..self.end]);
+        // Every octave of the buffer needs its seam.
+        self.seam_octaves = octaves(&seam, self.octaves.len());
+        self.seam = Some(seam);
+        self
+    }
+
+    /// Loop while the key is held, or not. Without a loop
+    /// the whole sample is played once.
+    pub fn with_looping(mut self, looped: bool) -> Self {
+        self.looped = looped;
+        self
+    }
+
+    /// Play the samples after the loop end once the key is
+    /// released, rather than looping until the envelope
+    /// ends.
+    pub fn with_release_tail(mut self, tail: bool) -> Self {
+        self.tail = tail;
+        self
+    }
+
+    /// Interpolate between samples with the given quality.
+    pub fn with_quality(mut self, quality: Quality) -> Self {
+        self.resamplers = playback_resamplers(quality, self.rate);
+        self
+    }
+
+    /// Length of the release tail in seconds.
+    pub fn release_time(&self) -> f32 {
+        if self.tail && self.looped {
+            (self.buf.len() - self.end) as f32 / self.rate as f32
+        } else {
+            0.0
+        }
+    }
+
+    /// Frequency the sample was recorded at, if known.
+    pub fn freq(&self) -> Option<f32> {
+        self.freq
+    }
+
     /// Iterator over the samples of a loop, resampled
-    /// to the given target frequency.
-    pub fn iter_freq(&self, freq: f32) -> Samples<'_> {
-        let incr = match self.freq {
+    /// to the given target frequency and sample rate.
+    pub fn iter_freq(&self, freq: f32, rate: u32) -> Samples<'_> {
+        let pitch = match self.freq {
             Some(f) => freq / f,
             None => 1.0,
         };
-        let cutoff = 20_000.0 * f32::min(1.0, incr);
-        Samples::new(self, incr, cutoff)
+        let incr = pitch * self.rate as f32 / rate as f32;
+        Samples::new(self, incr)
     }
 }
 
 impl<'a> Voice<'a> for Loop {
-    fn iter_freq(&'a self, freq: f32) -> Box<Signal<'a>> {
-        Box::new(self.iter_freq(freq))
+    fn iter_freq(&'a self, freq: f32, rate: u32) -> Box<Signal<'a>> {
+        Box::new(self.iter_freq(freq, rate))
+    }
+}
+
+/// Convert a whole buffer of samples from one sample rate to
+/// another.
+pub fn resample(buf: &[f32], from: u32, to: u32) -> Vec<f32> {
+    if from == to {
+        return buf.to_vec();
+    }
+    let ratio = from as f32 / to as f32;
+    let cutoff = f32::min(20_000.0, 0.5 * from.min(to) as f32);
+    let n = (buf.len() as u64 * u64::from(to) / u64::from(from)) as usize;
+    let resampler = Resampler::new(CONVERT_QUALITY, cutoff, from as f32);
+    (0..n)
+        .map(|i| resampler.at(i as f32 * ratio, buf))
+        .collect()
+}
+
+#[test]
+// Check that a tone keeps its frequency and level through
+// rate conversion both up and down.
+fn test_resample() {
+    for (from, to) in [(44_100, 48_000), (96_000, 48_000), (48_000, 48_000)] {
+        let freq = 440.0;
+        let tone = |rate: u32, n: usize| -> Vec<f32> {
+            (0..n)
+                .map(|i| f32::sin(2.0 * PI * freq * i as f32 / rate as f32))
+                .collect()
+        };
+        let input = tone(from, from as usize / 10);
+        let output = resample(&input, from, to);
+        assert_eq!(to as usize / 10, output.len());
+        // Skip the edges, where the filter runs off the end.
+        let expected = tone(to, output.len());
+        let middle = 100..output.len() - 100;
+        let err = output[middle.clone()]
+            .iter()
+            .zip(&expected[middle])
+            .map(|(y, e)| (y - e).abs())
+            .fold(0.0, f32::max);
+        assert!(err < 0.01, "{} -> {}: {}", from, to, err);
+    }
+
+    // High tones below both Nyquist limits survive.
+    for (from, to) in [(96_000, 48_000), (44_100, 48_000)] {
+        let input: Vec<f32> = (0..from / 10)
+            .map(|i| f32::sin(2.0 * PI * 15_000.0 * i as f32 / from as f32))
+            .collect();
+        let output = resample(&input, from, to);
+        let middle = &output[100..output.len() - 100];
+        let rms = f32::sqrt(middle.iter().map(|s| s * s).sum::<f32>() / middle.len() as f32);
+        assert!(
+            (rms - f32::sqrt(0.5)).abs() < 0.02,
+            "{} -> {}: {}",
+            from,
+            to,
+            rms
+        );
     }
 }
 
@@ -194,16 +696,16 @@ impl<'a> Voice<'a> for Loop {
 // BSD Licensed per author.
 // Please see comment at end of file for original source and
 // licensing information.
-fn resamp(x: f32, indat: &[f32], fmax: f32, wnwdth: i64) -> f32 {
+pub(crate) fn resamp(x: f32, indat: &[f32], fmax: f32, fsr: f32, wnwdth: i64) -> f32 {
     let alim = indat.len();
     // Calc gain correction factor.
-    let r_g = 2.0 * fmax / SAMPLE_RATE as f32;
+    let r_g = 2.0 * fmax / fsr;
     let mut r_y = 0.0;
     for i in -wnwdth / 2..wnwdth / 2 - 1 {
         // Calc input sample index.
         let j = (x + i as f32).floor();
         let r_w = 0.5 - 0.5 * f32::cos(2.0 * PI * (0.5 + (j - x) / wnwdth as f32));
-        let r_a = 2.0 * PI * (j - x) * fmax / SAMPLE_RATE as f32;
+        let r_a = 2.0 * PI * (j - x) * fmax / fsr;
         let r_snc = if j - x == 0.0 {
             1.0
         } else {
@@ -257,3 +759,246 @@ fn resamp(x: f32, indat: &[f32], fmax: f32, wnwdth: i64) -> f32 {
 // rem    for the student.
 // rem  (consider this code Open Source under a BSD style license)
 // rem  IMHO. YMMV.  http://www.nicholson.com/rhn/dsp.html
+
+#[test]
+// Check that a loop from metadata uses its root and loop
+// points without analysis.
+fn test_loop_meta() {
+    let buf: Vec<f32> = (0..100).map(|i| i as f32 / 100.0).collect();
+    let meta = SampleMeta {
+        root: Some(57.0),
+        sustain: Some(20..60),
+        ..SampleMeta::default()
+    };
+    let sloop = Loop::from_sample(&buf, DEFAULT_SAMPLE_RATE, &meta);
+    assert!((sloop.freq().unwrap() - 220.0).abs() < 1.0e-3);
+    assert_eq!((20, 60), (sloop.start, sloop.end));
+
+    // Playing at the root key steps through the samples
+    // one at a time, going back to the loop start.
+    let mut samples = sloop.iter_freq(220.0, DEFAULT_SAMPLE_RATE);
+    let mut xs = Vec::new();
+    for _ in 0..100 {
+        xs.push(samples.x);
+        samples.next();
+    }
+    assert!((xs[59] - 59.0).abs() < 1.0e-3);
+    assert!((xs[60] - 20.0).abs() < 1.0e-3);
+    assert!(xs.iter().all(|&x| x < 60.0));
+
+    // Empty or inverted loops are found by analysis instead.
+    let inverted = std::ops::Range { start: 60, end: 20 };
+    for sustain in [1..1, inverted] {
+        let meta = SampleMeta {
+            sustain: Some(sustain),
+            ..meta.clone()
+        };
+        let sloop = Loop::from_sample(&buf, DEFAULT_SAMPLE_RATE, &meta);
+        assert!(sloop.start < sloop.end);
+        assert_eq!(
+            100,
+            sloop
+                .iter_freq(220.0, DEFAULT_SAMPLE_RATE)
+                .take(100)
+                .count()
+        );
+    }
+}
+
+#[test]
+// Check that a held note loops, and that a released note
+// plays out its tail and ends, or keeps looping without
+// one.
+fn test_loop_release() {
+    let buf: Vec<f32> = (0..100).map(|i| i as f32 / 100.0).collect();
+    let meta = SampleMeta {
+        root: Some(57.0),
+        sustain: Some(20..60),
+        ..SampleMeta::default()
+    };
+    let sloop = Loop::from_sample(&buf, DEFAULT_SAMPLE_RATE, &meta);
+    assert_eq!(0.0, sloop.release_time());
+    let mut samples = sloop.iter_freq(220.0, DEFAULT_SAMPLE_RATE);
+    samples.release();
+    assert_eq!(1000, samples.by_ref().take(1000).count());
+
+    let sloop = sloop.with_release_tail(true);
+    let tail = 40.0 / DEFAULT_SAMPLE_RATE as f32;
+    assert!((sloop.release_time() - tail).abs() < 1.0e-6);
+    let mut samples = sloop.iter_freq(220.0, DEFAULT_SAMPLE_RATE);
+    assert_eq!(1000, samples.by_ref().take(1000).count());
+    assert!(samples.x < 60.0);
+    samples.release();
+    let rest = samples.by_ref().count();
+    assert!(rest > 40 && rest <= 80, "{}", rest);
+
+    // Striking the key again starts over.
+    samples.retrigger();
+    assert_eq!(1000, samples.take(1000).count());
+
+    // Far above the root, the tail is read from a decimated
+    // copy but still ends the note.
+    let buf: Vec<f32> = (0..2000).map(|i| f32::sin(i as f32 / 10.0)).collect();
+    let meta = SampleMeta {
+        sustain: Some(200..600),
+        ..meta
+    };
+    let sloop = Loop::from_sample(&buf, DEFAULT_SAMPLE_RATE, &meta).with_release_tail(true);
+    for octave in 1..5 {
+        let freq = 220.0 * (1 << octave) as f32;
+        let mut samples = sloop.iter_freq(freq, DEFAULT_SAMPLE_RATE);
+        assert_eq!(1000, samples.by_ref().take(1000).count());
+        samples.release();
+        let rest = samples.by_ref().take(10_000).count();
+        assert!(rest > 0 && rest < 2000 >> octave, "{}: {}", freq, rest);
+    }
+}
+
+#[test]
+// Check that a crossfaded loop has no jump at the seam,
+// both while held and on into the release tail, and that
+// playback starts at a zero crossing.
+fn test_crossfade() {
+    // A tone whose tail dies away, as a real release would.
+    let period = 37.3;
+    let buf: Vec<f32> = (0..2000)
+        .map(|i| {
+            let decay = f32::min(1.0, (2000 - i) as f32 / 400.0);
+            decay * f32::sin(2.0 * PI * (i as f32 + 10.0) / period)
+        })
+        .collect();
+    let meta = SampleMeta {
+        root: Some(69.0),
+        sustain: Some(500..1500),
+        ..SampleMeta::default()
+    };
+    let max_step = |samples: &mut Samples, n| {
+        let out: Vec<f32> = samples.take(n).collect();
+        out.windows(2)
+            .map(|w| (w[1] - w[0]).abs())
+            .fold(0.0, f32::max)
+    };
+    // Largest step of the tone itself, allowing for the
+    // swell of an equal-power fade between partly
+    // correlated signals.
+    let smooth = 2.0 * PI / period;
+    let fuzz = 1.25;
+
+    let hard = Loop::from_sample(&buf, DEFAULT_SAMPLE_RATE, &meta);
+    let mut samples = hard.iter_freq(440.0, DEFAULT_SAMPLE_RATE);
+    assert!(max_step(&mut samples, 5000) > 2.0 * smooth);
+
+    let time = 200.0 / DEFAULT_SAMPLE_RATE as f32;
+    let sloop = Loop::from_sample(&buf, DEFAULT_SAMPLE_RATE, &meta)
+        .with_crossfade(time)
+        .with_release_tail(true);
+    let mut samples = sloop.iter_freq(440.0, DEFAULT_SAMPLE_RATE);
+    assert!(samples.next().unwrap().abs() < 0.05);
+    assert!(max_step(&mut samples, 5000) < fuzz * smooth);
+    samples.release();
+    assert!(max_step(&mut samples, 5000) < fuzz * smooth);
+    assert!(samples.next().is_none());
+    samples.retrigger();
+    assert!(samples.next().unwrap().abs() < 0.05);
+}
+
+#[test]
+// Check that an unlooped sample plays once and ends.
+fn test_no_loop() {
+    let meta = SampleMeta {
+        root: Some(69.0),
+        sustain: Some(20..60),
+        ..SampleMeta::default()
+    };
+    let sloop = Loop::from_sample(&[0.5; 100], DEFAULT_SAMPLE_RATE, &meta).with_looping(false);
+    let mut samples = sloop.iter_freq(440.0, DEFAULT_SAMPLE_RATE);
+    assert_eq!(100, samples.by_ref().count());
+    samples.retrigger();
+    assert_eq!(100, samples.count());
+}
+
+#[test]
+// Check that a short noisy sample is left unpitched and
+// looped whole.
+fn test_loop_unpitched() {
+    let mut rng = Rng::new(2);
+    let noise: Vec<f32> = (0..100).map(|_| rng.uniform() - 0.5).collect();
+    let sloop = Loop::new(&noise, DEFAULT_SAMPLE_RATE);
+    assert_eq!(None, sloop.freq());
+    assert_eq!((0, 100), (sloop.start, sloop.end));
+    assert_eq!(
+        1000,
+        sloop
+            .iter_freq(440.0, DEFAULT_SAMPLE_RATE)
+            .take(1000)
+            .count()
+    );
+}
+
+#[test]
+// Check that a sample can be played many octaves up without
+// aliasing: tones that stay below the output Nyquist limit
+// keep their level, and tones pushed above it vanish.
+fn test_transpose_up() {
+    let rate = DEFAULT_SAMPLE_RATE;
+    let meta = SampleMeta {
+        root: Some(69.0),
+        sustain: Some(0..rate as usize),
+        ..SampleMeta::default()
+    };
+    let rms = |freq: f32, to: f32| {
+        let buf: Vec<f32> = (0..rate)
+            .map(|i| f32::sin(2.0 * PI * freq * i as f32 / rate as f32))
+            .collect();
+        let sloop = Loop::from_sample(&buf, rate, &meta);
+        let out: Vec<f32> = sloop.iter_freq(to, rate).skip(1000).take(4800).collect();
+        f32::sqrt(out.iter().map(|s| s * s).sum::<f32>() / out.len() as f32)
+    };
+    // Ten times up: 1 kHz to 10 kHz, and 3 kHz to 30 kHz.
+    assert!((rms(1000.0, 4400.0) - f32::sqrt(0.5)).abs() < 0.05);
+    assert!(rms(3000.0, 4400.0) < 0.01);
+    // Far beyond any key: nothing survives, but nothing
+    // breaks.
+    assert!(rms(1000.0, 440.0 * 1000.0) < 0.01);
+}
+
+#[test]
+// Check that notes share the loop's filters, picking one
+// whose cutoff allows for the transposition.
+fn test_playback_filters() {
+    let sloop = Loop::new(&[0.0; 1000], DEFAULT_SAMPLE_RATE);
+    let filter = |incr: f32| {
+        let samples = Samples::new(&sloop, incr);
+        let i = sloop
+            .resamplers
+            .iter()
+            .position(|r| std::ptr::eq(r, samples.resampler))
+            .unwrap();
+        (samples.scale, i)
+    };
+    assert_eq!((1.0, 0), filter(0.5));
+    assert_eq!((1.0, 0), filter(1.0));
+    assert_eq!((1.0, 3), filter(1.5));
+    assert_eq!((2.0, 0), filter(2.0));
+    assert_eq!((4.0, 4), filter(7.9));
+}
+
+#[test]
+// Check that a crossfaded loop keeps its seam however far
+// above the root it is played, even when the seam and the
+// buffer differ in length.
+fn test_crossfade_octaves() {
+    let buf: Vec<f32> = (0..2000).map(|i| f32::sin(i as f32 / 10.0)).collect();
+    let meta = SampleMeta {
+        root: Some(69.0),
+        sustain: Some(100..500),
+        ..SampleMeta::default()
+    };
+    let sloop = Loop::from_sample(&buf, DEFAULT_SAMPLE_RATE, &meta).with_crossfade(0.001);
+    assert_eq!(sloop.octaves.len(), sloop.seam_octaves.len());
+    for octave in 0..=sloop.octaves.len() {
+        let samples = Samples::new(&sloop, (1u64 << octave) as f32);
+        assert_eq!((1u64 << octave) as f32, samples.scale);
+        assert!(!std::ptr::eq(samples.seam, samples.buf), "{}", octave);
+    }
+}
@@ -0,0 +1,554 @@
+// Copyright © 2022 Bart Massey
+// [This program is licensed under the "MIT License"]
+// Please see the file LICENSE in the source
+// distribution of this software for license terms.
+
+//! SFZ instrument reader.
+//!
+//! Reads `<control>`, `<global>`, `<master>`, `<group>` and
+//! `<region>` headers, with each region inheriting the
+//! opcodes of the headers above it. The opcodes understood
+//! are `default_path`, `sample`, `key`, `lokey`, `hikey`,
+//! `pitch_keycenter`, `lovel`, `hivel`, `loop_mode`,
+//! `loop_start`, `loop_end`, `tune`, `transpose`, `volume`,
+//! `pan`, `seq_length`, `seq_position`, `trigger` and the
+//! `ampeg_*` envelope. Others are ignored.
+//!
+//! Overlapping regions are not layered: each note plays one
+//! region. Since output is mono, `pan` has no effect.
+//! `one_shot` plays like `no_loop`, and regions triggered
+//! on release are skipped.
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use crate::*;
+
+/// How a region's sample loops.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoopMode {
+    /// Play the sample once.
+    NoLoop,
+    /// Play the sample once, even after the key is
+    /// released.
+    OneShot,
+    /// Loop until the note ends.
+    Continuous,
+    /// Loop while the key is held, then play the rest.
+    Sustain,
+}
+
+impl FromStr for LoopMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "no_loop" => Ok(LoopMode::NoLoop),
+            "one_shot" => Ok(LoopMode::OneShot),
+            "loop_continuous" => Ok(LoopMode::Continuous),
+            "loop_sustain" => Ok(LoopMode::Sustain),
+            _ => Err(format!("unknown loop mode: {}", s)),
+        }
+    }
+}
+
+/// Region amplitude envelope. Times are in seconds and the
+/// sustain is a level.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Ampeg {
+    pub delay: f32,
+    pub attack: f32,
+    pub hold: f32,
+    pub decay: f32,
+    pub sustain: f32,
+    pub release: f32,
+}
+
+impl Ampeg {
+    /// The envelope as breakpoints.
+    pub fn breakpoints(&self) -> Breakpoints {
+        Breakpoints::dahdsr(
+            self.delay,
+            self.attack,
+            self.hold,
+            self.decay,
+            self.sustain,
+            self.release,
+        )
+    }
+}
+
+/// A region of an SFZ file, with the opcodes it inherits
+/// filled in.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SfzRegion {
+    /// Sample file, relative to the SFZ file.
+    pub sample: PathBuf,
+    pub keys: RangeInclusive<u8>,
+    pub velocities: RangeInclusive<u8>,
+    /// Key at which the sample plays at its recorded pitch.
+    pub keycenter: u8,
+    /// Loop mode, if not left to the sample file.
+    pub loop_mode: Option<LoopMode>,
+    /// First sample of the loop, if not left to the sample
+    /// file.
+    pub loop_start: Option<usize>,
+    /// Sample after the end of the loop, if not left to
+    /// the sample file.
+    pub loop_end: Option<usize>,
+    /// Tuning in cents.
+    pub tune: f32,
+    /// Transposition in semitones.
+    pub transpose: f32,
+    /// Volume in dB.
+    pub volume: f32,
+    /// Pan from -100 (left) to 100 (right).
+    pub pan: f32,
+    pub ampeg: Ampeg,
+    /// Round-robin count and position, counting from 1.
+    pub seq: (u32, u32),
+}
+
+/// Opcode values with the line each came from.
+type Opcodes = HashMap<String, (String, usize)>;
+
+// Blank out comments, keeping line breaks so that line
+// numbers still match.
+fn strip_comments(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while !rest.is_empty() {
+        if let Some(after) = rest.strip_prefix("//") {
+            let end = after.find('\n').unwrap_or(after.len());
+            rest = &after[end..];
+        } else if let Some(after) = rest.strip_prefix("/*") {
+            let end = after.find("*/").map(|i| i + 2).unwrap_or(after.len());
+            out.extend(after[..end].chars().filter(|&c| c == '\n'));
+            rest = &after[end..];
+        } else {
+            let c = rest.chars().next().unwrap();
+            out.push(c);
+            rest = &rest[c.len_utf8()..];
+        }
+    }
+    out
+}
+
+// Split a line into headers and opcodes. A value runs up to
+// the next opcode name or header, so may contain spaces.
+fn tokens(line: &str) -> Result<Vec<(&str, Option<&str>)>, String> {
+    let mut tokens = Vec::new();
+    let mut rest = line.trim_start();
+    while !rest.is_empty() {
+        if let Some(after) = rest.strip_prefix('<') {
+            let end = after.find('>').ok_or("unclosed header")?;
+            tokens.push((&after[..end], None));
+            rest = after[end + 1..].trim_start();
+            continue;
+        }
+        let eq = rest.find('=').ok_or("expected opcode=value")?;
+        let name = &rest[..eq];
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            return Err(format!("bad opcode name `{}`", name.trim()));
+        }
+        let after = &rest[eq + 1..];
+        // The value ends at a header, or at the whitespace
+        // before the next name=.
+        let mut end = after.find('<').unwrap_or(after.len());
+        if let Some(next_eq) = after[..end].find('=') {
+            let before =
+                after[..next_eq].trim_end_matches(|c: char| c.is_ascii_alphanumeric() || c == '_');
+            end = before.trim_end().len();
+        }
+        tokens.push((name, Some(after[..end].trim())));
+        rest = after[end..].trim_start();
+    }
+    Ok(tokens)
+}
+
+/// Parse a MIDI key given as a number or a note name such
+/// as `c4`, `f#3` or `eb-1`, with `c4` being key 60.
+pub fn parse_key(s: &str) -> Option<u8> {
+    if let Ok(n) = s.parse::<u8>() {
+        return (n < 128).then_some(n);
+    }
+    let s = s.to_ascii_lowercase();
+    let mut chars = s.chars();
+    let pc = match chars.next()? {
+        'c' => 0,
+        'd' => 2,
+        'e' => 4,
+        'f' => 5,
+        'g' => 7,
+        'a' => 9,
+        'b' => 11,
+        _ => return None,
+    };
+    let rest = chars.as_str();
+    let (accidental, octave) = match rest.chars().next()? {
+        '#' => (1, &rest[1..]),
+        'b' => (-1, &rest[1..]),
+        _ => (0, rest),
+    };
+    let octave: i32 = octave.parse().ok()?;
+    let key = 12 * (octave + 1) + pc + accidental;
+    u8::try_from(key).ok().filter(|&k| k < 128)
+}
+
+// Make a region from its opcodes.
+fn region(opcodes: &Opcodes, control: &Opcodes, line: usize) -> Result<SfzRegion, String> {
+    let get = |name: &str| opcodes.get(name).map(|(v, l)| (v.as_str(), *l));
+    fn bad(name: &str, line: usize) -> String {
+        format!("sfz line {}: bad value for {}", line, name)
+    }
+    let key = |name: &str| -> Result<Option<u8>, String> {
+        get(name)
+            .map(|(v, l)| parse_key(v).ok_or_else(|| bad(name, l)))
+            .transpose()
+    };
+    let number = |name: &str, default: f32| -> Result<f32, String> {
+        get(name)
+            .map(|(v, l)| v.parse().map_err(|_| bad(name, l)))
+            .unwrap_or(Ok(default))
+    };
+    let count = |names: &[&str]| -> Result<Option<usize>, String> {
+        names
+            .iter()
+            .find_map(|&name| get(name).map(|(v, l)| v.parse().map_err(|_| bad(name, l))))
+            .transpose()
+    };
+    let velocity = |name: &str, default: u8| -> Result<u8, String> {
+        get(name)
+            .map(|(v, l)| {
+                v.parse()
+                    .ok()
+                    .filter(|&v: &u8| v < 128)
+                    .ok_or_else(|| bad(name, l))
+            })
+            .unwrap_or(Ok(default))
+    };
+
+    let (sample, _) = get("sample").ok_or(format!("sfz line {}: region has no sample", line))?;
+    let path = control
+        .get("default_path")
+        .map(|(v, _)| v.as_str())
+        .unwrap_or("");
+    let sample = PathBuf::from(format!("{}{}", path, sample).replace('\\', "/"));
+
+    let k = key("key")?;
+    let loop_mode = ["loop_mode", "loopmode"]
+        .iter()
+        .find_map(|&name| get(name).map(|(v, l)| v.parse().map_err(|_| bad(name, l))))
+        .transpose()?;
+    let seq_length = count(&["seq_length"])?.unwrap_or(1);
+    let seq_position = count(&["seq_position"])?.unwrap_or(1);
+    Ok(SfzRegion {
+        sample,
+        keys: key("lokey")?.or(k).unwrap_or(0)..=key("hikey")?.or(k).unwrap_or(127),
+        velocities: velocity("lovel", 1)?..=velocity("hivel", 127)?,
+        keycenter: key("pitch_keycenter")?.or(k).unwrap_or(60),
+        loop_mode,
+        loop_start: count(&["loop_start", "loopstart"])?,
+        // Inclusive in the file.
+        loop_end: count(&["loop_end", "loopend"])?.map(|e| e + 1),
+        tune: number("tune", 0.0)?,
+        transpose: number("transpose", 0.0)?,
+        volume: number("volume", 0.0)?,
+        pan: number("pan", 0.0)?,
+        ampeg: Ampeg {
+            delay: number("ampeg_delay", 0.0)?,
+            attack: number("ampeg_attack", 0.0)?,
+            hold: number("ampeg_hold", 0.0)?,
+            decay: number("ampeg_decay", 0.0)?,
+            sustain: number("ampeg_sustain", 100.0)? / 100.0,
+            release: number("ampeg_release", 0.001)?,
+        },
+        seq: (seq_length as u32, seq_position as u32),
+    })
+}
+
+/// Parse SFZ text into its regions, in file order.
+pub fn parse_sfz(text: &str) -> Result<Vec<SfzRegion>, Box<dyn Error>> {
+    #[derive(PartialEq)]
+    enum Scope {
+        None,
+        Control,
+        Global,
+        Master,
+        Group,
+        Region,
+    }
+    let mut scope = Scope::None;
+    let mut control = Opcodes::new();
+    // Global, master, group and region opcodes.
+    let mut levels: [Opcodes; 4] = Default::default();
+    let mut region_line = 0;
+    let mut regions = Vec::new();
+    let mut flush = |scope: &Scope, levels: &[Opcodes; 4], control: &Opcodes, line| {
+        if *scope != Scope::Region {
+            return Ok(());
+        }
+        let mut opcodes = Opcodes::new();
+        for level in levels {
+            opcodes.extend(level.iter().map(|(k, v)| (k.clone(), v.clone())));
+        }
+        // Regions triggered on release are not supported.
+        if opcodes.get("trigger").is_none_or(|(t, _)| t == "attack") {
+            regions.push(region(&opcodes, control, line)?);
+        }
+        Ok::<(), String>(())
+    };
+
+    for (i, line) in strip_comments(text).lines().enumerate() {
+        let lineno = i + 1;
+        if line.trim_start().starts_with('#') {
+            return Err(format!(
+                "sfz line {}: preprocessor directives are not supported",
+                lineno
+            )
+            .into());
+        }
+        for (name, value) in tokens(line).map_err(|e| format!("sfz line {}: {}", lineno, e))? {
+            let value = match value {
+                Some(value) => value,
+                None => {
+                    flush(&scope, &levels, &control, region_line)?;
+                    let clear_from = match name {
+                        "control" => {
+                            scope = Scope::Control;
+                            continue;
+                        }
+                        "global" => 0,
+                        "master" => 1,
+                        "group" => 2,
+                        "region" => 3,
+                        _ => {
+                            // Other headers' opcodes go nowhere.
+                            scope = Scope::None;
+                            continue;
+                        }
+                    };
+                    for level in &mut levels[clear_from..] {
+                        level.clear();
+                    }
+                    scope = [Scope::Global, Scope::Master, Scope::Group, Scope::Region]
+                        .into_iter()
+                        .nth(clear_from)
+                        .unwrap();
+                    region_line = lineno;
+                    continue;
+                }
+            };
+            let opcodes = match scope {
+                Scope::None => continue,
+                Scope::Control => &mut control,
+                Scope::Global => &mut levels[0],
+                Scope::Master => &mut levels[1],
+                Scope::Group => &mut levels[2],
+                Scope::Region => &mut levels[3],
+            };
+            opcodes.insert(name.to_string(), (value.to_string(), lineno));
+        }
+    }
+    flush(&scope, &levels, &control, region_line)?;
+    Ok(regions)
+}
+
+/// Read an SFZ file and its samples into an instrument
+/// playing at the given sample rate. Regions in a
+/// round-robin sequence with the same keys and velocities
+/// become alternates in one zone.
+pub fn load_sfz<P>(name: P, rate: u32) -> Result<Instrument, Box<dyn Error>>
+where
+    P: AsRef<Path>,
+{
+    let name = name.as_ref();
+    let dir = name.parent().unwrap_or_else(|| Path::new("."));
+    let text = std::fs::read_to_string(name).map_err(|e| format!("{}: {}", name.display(), e))?;
+    let regions = parse_sfz(&text).map_err(|e| format!("{}: {}", name.display(), e))?;
+    if regions.is_empty() {
+        return Err(format!("{}: no regions", name.display()).into());
+    }
+
+    // Alternates, with their positions and envelopes, for
+    // each zone.
+    type Alternate = (u32, Ampeg, Loop);
+    let mut zones: Vec<(SfzRegion, Vec<Alternate>)> = Vec::new();
+    for region in regions {
+        let path = dir.join(&region.sample);
+        let (signal, file_rate, mut meta) =
+            read_sample(&path, None).map_err(|e| format!("{}: {}", path.display(), e))?;
+
+        // Opcodes override the sample file.
+        meta.root = Some(f32::from(region.keycenter) - region.transpose - region.tune / 100.0);
+        meta.gain = region.volume;
+        let start = region.loop_start.or(meta.sustain.as_ref().map(|l| l.start));
+        let end = region.loop_end.or(meta.sustain.as_ref().map(|l| l.end));
+        meta.sustain = match (start, end) {
+            (Some(start), Some(end)) if start < end && end <= signal.len() => Some(start..end),
+            _ => None,
+        };
+        let mode = region.loop_mode.unwrap_or(if meta.sustain.is_some() {
+            LoopMode::Continuous
+        } else {
+            LoopMode::NoLoop
+        });
+        let looped =
+            matches!(mode, LoopMode::Continuous | LoopMode::Sustain) && meta.sustain.is_some();
+        if !looped {
+            // Nothing to find a loop in.
+            meta.sustain = Some(0..signal.len());
+        }
+        let meta = meta.resampled(file_rate, rate);
+        let sloop = Loop::from_sample(&resample(&signal, file_rate, rate), rate, &meta)
+            .with_looping(looped)
+            .with_release_tail(mode == LoopMode::Sustain);
+
+        let (length, position) = region.seq;
+        let alternate = zones.iter_mut().find(|(r, _)| {
+            length > 1
+                && r.seq.0 == length
+                && r.keys == region.keys
+                && r.velocities == region.velocities
+        });
+        let ampeg = region.ampeg;
+        match alternate {
+            Some((_, loops)) => loops.push((position, ampeg, sloop)),
+            None => zones.push((region, vec![(position, ampeg, sloop)])),
+        }
+    }
+
+    let zones = zones
+        .into_iter()
+        .map(|(region, mut loops)| {
+            // The first in the sequence sets the envelope.
+            loops.sort_by_key(|(position, _, _)| *position);
+            let env = loops[0].1.breakpoints();
+            let loops = loops.into_iter().map(|(_, _, sloop)| sloop).collect();
+            Zone::new(region.keys, region.velocities, loops).with_envelope(env)
+        })
+        .collect();
+    Ok(Instrument::new(zones))
+}
+
+#[test]
+// Check note names.
+fn test_parse_key() {
+    assert_eq!(Some(60), parse_key("c4"));
+    assert_eq!(Some(60), parse_key("60"));
+    assert_eq!(Some(66), parse_key("F#4"));
+    assert_eq!(Some(70), parse_key("bb4"));
+    assert_eq!(Some(0), parse_key("c-1"));
+    assert_eq!(Some(127), parse_key("g9"));
+    assert_eq!(None, parse_key("a9"));
+    assert_eq!(None, parse_key("h2"));
+    assert_eq!(None, parse_key("128"));
+}
+
+#[test]
+// Check headers, inheritance, comments and values with
+// spaces.
+fn test_parse_sfz() {
+    let text = "
+        // A test instrument.
+        <control> default_path=samples\\
+        <global> ampeg_release=0.5 volume=-3
+        <group> lovel=1 hivel=63 /* soft
+            layer */ loop_mode=loop_sustain
+        <region> sample=soft piano c4.wav key=c4 tune=-10
+        <region> sample=soft d4.wav lokey=61 hikey=63 pitch_keycenter=d4
+            loop_start=100 loop_end=199
+        <group> seq_length=2 seq_position=2
+        <region> sample=hard.wav volume=0 ampeg_sustain=50
+        <region> sample=release.wav trigger=release
+        <curve> v000=0
+    ";
+    let regions = parse_sfz(text).unwrap();
+    assert_eq!(3, regions.len());
+
+    let r = &regions[0];
+    assert_eq!(PathBuf::from("samples/soft piano c4.wav"), r.sample);
+    assert_eq!(60..=60, r.keys);
+    assert_eq!(60, r.keycenter);
+    assert_eq!(1..=63, r.velocities);
+    assert_eq!(Some(LoopMode::Sustain), r.loop_mode);
+    assert_eq!(-10.0, r.tune);
+    assert_eq!(-3.0, r.volume);
+    assert_eq!(0.5, r.ampeg.release);
+    assert_eq!((None, None), (r.loop_start, r.loop_end));
+
+    let r = &regions[1];
+    assert_eq!(61..=63, r.keys);
+    assert_eq!(62, r.keycenter);
+    assert_eq!((Some(100), Some(200)), (r.loop_start, r.loop_end));
+
+    // The new group drops the old group's opcodes.
+    let r = &regions[2];
+    assert_eq!(0..=127, r.keys);
+    assert_eq!(1..=127, r.velocities);
+    assert_eq!(None, r.loop_mode);
+    assert_eq!(0.0, r.volume);
+    assert_eq!(0.5, r.ampeg.sustain);
+    assert_eq!((2, 2), r.seq);
+
+    for bad in [
+        "<region> lokey=c4",
+        "<region> sample=a.wav lokey=x",
+        "<region> sample=a.wav loop_mode=sometimes",
+        "<region sample=a.wav",
+        "#define $X 1",
+    ] {
+        assert!(parse_sfz(bad).is_err(), "{}", bad);
+    }
+}
+
+#[test]
+// Check that an SFZ file and its samples load into zones
+// with round-robin alternates.
+fn test_load_sfz() {
+    let dir = std::env::temp_dir().join(format!("rustsy-sfz-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let spec = hound::WavSpec {
+        channels: 1,
+        sample_rate: 44_100,
+        bits_per_sample: 16,
+        sample_format: hound::SampleFormat::Int,
+    };
+    for name in ["a.wav", "b.wav"] {
+        let mut wav = hound::WavWriter::create(dir.join(name), spec).unwrap();
+        // Start from silence, as a real sample would.
+        for i in 0..1000 {
+            let t = i.max(10) as f32 - 10.0;
+            let s = f32::sin(2.0 * std::f32::consts::PI * 440.0 * t / 44_100.0);
+            wav.write_sample((s * 16_000.0) as i16).unwrap();
+        }
+        wav.finalize().unwrap();
+    }
+    let sfz = dir.join("test.sfz");
+    std::fs::write(
+        &sfz,
+        "<group> hikey=60 <region> sample=a.wav
+         <group> lokey=61 seq_length=2 loop_start=100 loop_end=199
+         <region> sample=b.wav seq_position=2
+         <region> sample=a.wav seq_position=1 ampeg_release=0.25",
+    )
+    .unwrap();
+    let instrument = load_sfz(&sfz, DEFAULT_SAMPLE_RATE).unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    assert_eq!(2, instrument.zones().len());
+    assert_eq!(0..=60, instrument.zones()[0].keys);
+    assert_eq!(61..=127, instrument.zones()[1].keys);
+    assert_eq!(0.25, instrument.release_time());
+
+    // The unlooped sample plays once, resampled.
+    let once = instrument.iter_note(261.63, 100, DEFAULT_SAMPLE_RATE);
+    let n = once.count();
+    assert!((1080..1100).contains(&n), "{}", n);
+    // The looped one keeps going.
+    let looped = instrument.iter_note(440.0, 100, DEFAULT_SAMPLE_RATE);
+    assert_eq!(10_000, looped.take(10_000).count());
+}
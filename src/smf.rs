@@ -0,0 +1,297 @@
+// Copyright © 2022 Bart Massey
+// [This program is licensed under the "MIT License"]
+// Please see the file LICENSE in the source
+// distribution of this software for license terms.
+
+//! Standard MIDI File reader.
+//!
+//! Reads format 0 and 1 files into a timed list of key
+//! events, following the tempo map. Only note events are
+//! kept: everything else is skipped.
+
+use std::convert::TryFrom;
+use std::error::Error;
+
+use wmidi::{Channel, MidiMessage, U7};
+
+use crate::*;
+
+/// Default tempo in microseconds per quarter note.
+const DEFAULT_TEMPO: u32 = 500_000;
+
+/// Cursor over the bytes of a file.
+struct Bytes<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Bytes<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], Box<dyn Error>> {
+        let end = self
+            .pos
+            .checked_add(n)
+            .filter(|&end| end <= self.data.len())
+            .ok_or("MIDI file truncated")?;
+        let bytes = &self.data[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn byte(&mut self) -> Result<u8, Box<dyn Error>> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, Box<dyn Error>> {
+        let b = self.take(2)?;
+        Ok(u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    fn u32(&mut self) -> Result<u32, Box<dyn Error>> {
+        let b = self.take(4)?;
+        Ok(u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    // Variable-length quantity: seven bits per byte, high
+    // bit set on all but the last. At most four bytes.
+    fn varlen(&mut self) -> Result<u32, Box<dyn Error>> {
+        let mut n = 0;
+        for _ in 0..4 {
+            let b = self.byte()?;
+            n = (n << 7) | u32::from(b & 0x7f);
+            if b & 0x80 == 0 {
+                return Ok(n);
+            }
+        }
+        Err("MIDI variable-length number too long".into())
+    }
+
+    // Next chunk: type and contents.
+    fn chunk(&mut self) -> Result<([u8; 4], Bytes<'a>), Box<dyn Error>> {
+        let tag = self.take(4)?;
+        let len = self.u32()? as usize;
+        let data = self.take(len)?;
+        let tag = [tag[0], tag[1], tag[2], tag[3]];
+        Ok((tag, Bytes { data, pos: 0 }))
+    }
+
+    fn is_empty(&self) -> bool {
+        self.pos >= self.data.len()
+    }
+}
+
+/// Something that happens at a tick.
+enum Timed {
+    Tempo(u32),
+    Message(MidiMessage<'static>),
+}
+
+// Read the events of a track, with their absolute times in
+// ticks.
+fn read_track(mut track: Bytes, timed: &mut Vec<(u64, Timed)>) -> Result<(), Box<dyn Error>> {
+    let mut tick = 0u64;
+    let mut running = None;
+    while !track.is_empty() {
+        tick += u64::from(track.varlen()?);
+        let mut status = track.byte()?;
+        match status {
+            0xff => {
+                let kind = track.byte()?;
+                let len = track.varlen()? as usize;
+                let data = track.take(len)?;
+                match kind {
+                    0x2f => return Ok(()),
+                    0x51 if len == 3 => {
+                        let tempo = u32::from_be_bytes([0, data[0], data[1], data[2]]);
+                        timed.push((tick, Timed::Tempo(tempo)));
+                    }
+                    _ => (),
+                }
+                continue;
+            }
+            0xf0 | 0xf7 => {
+                let len = track.varlen()? as usize;
+                track.take(len)?;
+                running = None;
+                continue;
+            }
+            0xf1..=0xfe => return Err(format!("bad MIDI file status {:02x}", status).into()),
+            _ => (),
+        }
+        // Channel message, perhaps with running status.
+        let first = if status & 0x80 == 0 {
+            let data = status;
+            status = running.ok_or("MIDI running status without status")?;
+            data
+        } else {
+            running = Some(status);
+            track.byte()?
+        };
+        let channel = Channel::from_index(status & 0x0f)?;
+        let len = match status & 0xf0 {
+            0xc0 | 0xd0 => 1,
+            _ => 2,
+        };
+        let second = if len == 2 { track.byte()? } else { 0 };
+        let key = || U7::try_from(first).map(wmidi::Note::from);
+        let velocity = || U7::try_from(second);
+        let message = match status & 0xf0 {
+            0x90 if second > 0 => MidiMessage::NoteOn(channel, key()?, velocity()?),
+            0x80 | 0x90 => MidiMessage::NoteOff(channel, key()?, velocity()?),
+            _ => continue,
+        };
+        timed.push((tick, Timed::Message(message)));
+    }
+    Ok(())
+}
+
+/// Parse a format 0 or 1 Standard MIDI File into note events
+/// in time order.
+pub fn parse_smf(data: &[u8]) -> Result<Vec<Event>, Box<dyn Error>> {
+    let mut file = Bytes { data, pos: 0 };
+    let (tag, mut header) = file.chunk()?;
+    if &tag != b"MThd" {
+        return Err("not a MIDI file".into());
+    }
+    let format = header.u16()?;
+    let ntracks = header.u16()?;
+    let division = header.u16()?;
+    if format > 1 {
+        return Err(format!("unsupported MIDI file format {}", format).into());
+    }
+
+    // Seconds per tick is either set by the tempo, or fixed
+    // for SMPTE time.
+    let (ticks_per_quarter, smpte) = if division & 0x8000 == 0 {
+        if division == 0 {
+            return Err("MIDI file has zero division".into());
+        }
+        (f64::from(division), None)
+    } else {
+        let fps = match (division >> 8) as u8 as i8 {
+            -24 => 24.0,
+            -25 => 25.0,
+            -29 => 29.97,
+            -30 => 30.0,
+            fps => return Err(format!("bad MIDI file SMPTE rate {}", fps).into()),
+        };
+        let ticks_per_frame = f64::from(division & 0xff);
+        (1.0, Some(1.0 / (fps * ticks_per_frame)))
+    };
+
+    let mut timed = Vec::new();
+    let mut ntrack = 0;
+    while !file.is_empty() && ntrack < ntracks {
+        let (tag, track) = file.chunk()?;
+        // Unknown chunks are to be skipped.
+        if &tag == b"MTrk" {
+            read_track(track, &mut timed)?;
+            ntrack += 1;
+        }
+    }
+    if ntrack < ntracks {
+        return Err("MIDI file is missing tracks".into());
+    }
+
+    // Merge the tracks. The sort is stable, so at equal ticks
+    // earlier tracks (with the tempo map) go first.
+    timed.sort_by_key(|(tick, _)| *tick);
+    let mut events = Vec::new();
+    let mut tempo = DEFAULT_TEMPO;
+    let mut last = 0;
+    let mut time = 0.0f64;
+    for (tick, t) in timed {
+        let seconds_per_tick = smpte.unwrap_or(f64::from(tempo) / 1_000_000.0 / ticks_per_quarter);
+        time += (tick - last) as f64 * seconds_per_tick;
+        last = tick;
+        match t {
+            Timed::Tempo(t) => tempo = t,
+            Timed::Message(message) => events.push(Event {
+                time: time as f32,
+                message,
+            }),
+        }
+    }
+    Ok(events)
+}
+
+/// Read note events from a Standard MIDI File. See
+/// [parse_smf].
+pub fn read_midi_file<P>(name: P) -> Result<Vec<Event>, Box<dyn Error>>
+where
+    P: AsRef<std::path::Path>,
+{
+    parse_smf(&std::fs::read(name)?)
+}
+
+#[cfg(test)]
+// Wrap track data up as a file with the given format.
+fn smf(format: u16, division: u16, tracks: &[&[u8]]) -> Vec<u8> {
+    let mut data = b"MThd".to_vec();
+    data.extend(6u32.to_be_bytes());
+    data.extend(format.to_be_bytes());
+    data.extend((tracks.len() as u16).to_be_bytes());
+    data.extend(division.to_be_bytes());
+    for track in tracks {
+        data.extend(b"MTrk");
+        data.extend((track.len() as u32).to_be_bytes());
+        data.extend(*track);
+    }
+    data
+}
+
+#[test]
+// Check a format 1 file with a tempo change, running status
+// and a note-on used as a note-off.
+fn test_smf_format1() {
+    let tempo: &[u8] = &[
+        0x00, 0xff, 0x51, 0x03, 0x07, 0xa1, 0x20, // 120 bpm
+        0x81, 0x40, 0xff, 0x51, 0x03, 0x03, 0xd0, 0x90, // tick 192: 240 bpm
+        0x00, 0xff, 0x2f, 0x00,
+    ];
+    let notes: &[u8] = &[
+        0x00, 0x90, 0x3c, 0x64, // on 60
+        0x60, 0x3c, 0x00, // running: off 60 at tick 96
+        0x60, 0xf0, 0x01, 0xf7, // sysex cancels running status
+        0x00, 0x91, 0x40, 0x50, // on 64 at tick 192, channel 2
+        0x00, 0xb1, 0x07, 0x64, // controller: skipped
+        0x60, 0x81, 0x40, 0x00, // off 64 at tick 288
+        0x00, 0xff, 0x2f, 0x00,
+    ];
+    let events = parse_smf(&smf(1, 96, &[tempo, notes])).unwrap();
+    let times: Vec<f32> = events.iter().map(|e| e.time).collect();
+    assert_eq!(vec![0.0, 0.5, 1.0, 1.25], times);
+    let key = |k| wmidi::Note::from(U7::try_from(k).unwrap());
+    let vel = |v| U7::try_from(v).unwrap();
+    assert_eq!(
+        MidiMessage::NoteOn(Channel::Ch1, key(60), vel(100)),
+        events[0].message
+    );
+    assert_eq!(
+        MidiMessage::NoteOff(Channel::Ch1, key(60), vel(0)),
+        events[1].message
+    );
+    assert_eq!(
+        MidiMessage::NoteOn(Channel::Ch2, key(64), vel(80)),
+        events[2].message
+    );
+    assert!(matches!(events[3].message, MidiMessage::NoteOff(..)));
+}
+
+#[test]
+// Check a format 0 file with SMPTE timing, and some broken
+// files.
+fn test_smf_format0() {
+    // 25 fps, 40 ticks per frame: a millisecond per tick.
+    let division = 0xe728;
+    let track: &[u8] = &[0x00, 0x90, 0x3c, 0x64, 0x87, 0x68, 0x3c, 0x00];
+    let events = parse_smf(&smf(0, division, &[track])).unwrap();
+    assert_eq!(2, events.len());
+    assert!((events[1].time - 1.0).abs() < 1.0e-6);
+
+    let truncated = smf(0, 96, &[&track[..7]]);
+    assert!(parse_smf(&truncated).is_err());
+    let no_status: &[u8] = &[0x00, 0x3c, 0x64];
+    assert!(parse_smf(&smf(0, 96, &[no_status])).is_err());
+    assert!(parse_smf(&smf(2, 96, &[track])).is_err());
+    assert!(parse_smf(b"RIFF").is_err());
+}
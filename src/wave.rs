@@ -1,4 +1,5 @@
 use std::f32::consts::{PI, TAU};
+use std::sync::atomic::{AtomicU64, Ordering};
 
 use crate::*;
 
@@ -8,12 +9,102 @@ pub enum WaveShape {
     Square,
     Saw,
     Tri,
+    /// Pulse wave whose duty cycle is set by the generator.
+    Pulse,
+    /// White noise. Noise shapes ignore the note frequency.
+    White,
+    /// Pink noise: equal energy per octave.
+    Pink,
+    /// Brown (red) noise: integrated white noise.
+    Brown,
 }
 
-struct Wave {
+impl WaveShape {
+    /// Naive value of the wave at phase `t` radians, for `t`
+    /// in `0..TAU`. Noise has no phase: noise shapes give
+    /// white noise hashed from `t`, which is what a noise
+    /// frame of a wavetable wants.
+    pub(crate) fn raw(self, t: f32) -> f32 {
+        match self {
+            WaveShape::Sine => f32::sin(t),
+            WaveShape::Square => square(t),
+            WaveShape::Saw => saw(t),
+            WaveShape::Tri => tri(t),
+            WaveShape::Pulse => pulse(t, 0.5),
+            WaveShape::White | WaveShape::Pink | WaveShape::Brown => {
+                Rng::new(t.to_bits() as u64).uniform()
+            }
+        }
+    }
+}
+
+/// How a wave is rendered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WaveMode {
+    /// Naive waveforms computed directly from the phase.
+    /// These alias badly at high frequencies, and are kept
+    /// mostly for comparison.
+    Raw,
+    /// Band-limited waveforms: discontinuities are smoothed
+    /// with PolyBLEP and corners with PolyBLAMP.
+    BandLimited,
+}
+
+/// Pulse-width modulation source.
+pub enum Pwm {
+    /// Sine LFO with the given rate in Hz, swinging the duty
+    /// cycle by `depth` either way.
+    Lfo { rate: f32, depth: f32 },
+    /// Envelope moving the duty cycle by `depth` times the
+    /// envelope level.
+    Envelope { env: Breakpoints, depth: f32 },
+}
+
+/// Running state of a PWM source.
+enum PwmState<'a> {
+    Lfo { t: f32, dt: f32, depth: f32 },
+    Envelope { envelope: Envelope<'a>, depth: f32 },
+}
+
+impl<'a> PwmState<'a> {
+    fn new(pwm: &'a Pwm, sample_rate: u32) -> Self {
+        match *pwm {
+            Pwm::Lfo { rate, depth } => PwmState::Lfo {
+                t: 0.0,
+                dt: TAU * rate / sample_rate as f32,
+                depth,
+            },
+            Pwm::Envelope { ref env, depth } => PwmState::Envelope {
+                envelope: Envelope::new(env, sample_rate),
+                depth,
+            },
+        }
+    }
+
+    // Next duty-cycle offset.
+    fn next(&mut self) -> f32 {
+        match self {
+            PwmState::Lfo { t, dt, depth } => {
+                let m = *depth * f32::sin(*t);
+                *t += *dt;
+                while *t >= TAU {
+                    *t -= TAU;
+                }
+                m
+            }
+            PwmState::Envelope { envelope, depth } => *depth * envelope.next().unwrap_or(0.0),
+        }
+    }
+}
+
+struct Wave<'a> {
     t: f32,
     dt: f32,
-    f: fn(f32) -> f32,
+    shape: WaveShape,
+    mode: WaveMode,
+    duty: f32,
+    pwm: Option<PwmState<'a>>,
+    noise: Option<Noise>,
 }
 
 fn square(t: f32) -> f32 {
@@ -28,6 +119,15 @@ fn saw(t: f32) -> f32 {
     1.0 - (2.0 / TAU) * t
 }
 
+// Pulse that is high for fraction `duty` of the cycle, at
+// the end, so that duty 0.5 is a square wave. The DC offset
+// is removed, so that changing the duty cycle does not
+// thump.
+fn pulse(t: f32, duty: f32) -> f32 {
+    let s = if t < TAU * (1.0 - duty) { -1.0 } else { 1.0 };
+    s - (2.0 * duty - 1.0)
+}
+
 fn tri(t: f32) -> f32 {
     if t < PI {
         1.0 - (2.0 / PI) * t
@@ -36,46 +136,340 @@ fn tri(t: f32) -> f32 {
     }
 }
 
-impl Wave {
-    fn new(freq: f32, shape: WaveShape) -> Self {
-        let shapefn = match shape {
-            WaveShape::Sine => f32::sin,
-            WaveShape::Square => square,
-            WaveShape::Saw => saw,
-            WaveShape::Tri => tri,
-        };
+// Two-sample polynomial band-limited step residual for a
+// step of height 2 at phase 0. `p` is the phase and `dp`
+// the phase increment, both in cycles.
+fn poly_blep(p: f32, dp: f32) -> f32 {
+    if p < dp {
+        let x = p / dp;
+        2.0 * x - x * x - 1.0
+    } else if p > 1.0 - dp {
+        let x = (p - 1.0) / dp;
+        x * x + 2.0 * x + 1.0
+    } else {
+        0.0
+    }
+}
+
+// Two-sample polynomial band-limited ramp residual for a
+// change of slope of 2 per sample at phase 0. This is the
+// integral of the PolyBLEP residual.
+fn poly_blamp(p: f32, dp: f32) -> f32 {
+    if p < dp {
+        let x = p / dp - 1.0;
+        -x * x * x / 3.0
+    } else if p > 1.0 - dp {
+        let x = (p - 1.0) / dp + 1.0;
+        x * x * x / 3.0
+    } else {
+        0.0
+    }
+}
+
+// Shift a phase in cycles by a half cycle.
+fn half_turn(p: f32) -> f32 {
+    let p = p + 0.5;
+    if p >= 1.0 {
+        p - 1.0
+    } else {
+        p
+    }
+}
+
+fn square_bl(t: f32, dt: f32) -> f32 {
+    let (p, dp) = (t / TAU, dt / TAU);
+    // Falling edge at 0, rising edge at one-half.
+    square(t) - poly_blep(p, dp) + poly_blep(half_turn(p), dp)
+}
+
+fn saw_bl(t: f32, dt: f32) -> f32 {
+    let (p, dp) = (t / TAU, dt / TAU);
+    // Rising edge at 0.
+    saw(t) + poly_blep(p, dp)
+}
+
+fn pulse_bl(t: f32, dt: f32, duty: f32) -> f32 {
+    let (p, dp) = (t / TAU, dt / TAU);
+    // Falling edge at 0, rising edge at 1 - duty.
+    let q = p + duty;
+    let q = if q >= 1.0 { q - 1.0 } else { q };
+    pulse(t, duty) - poly_blep(p, dp) + poly_blep(q, dp)
+}
+
+fn tri_bl(t: f32, dt: f32) -> f32 {
+    let (p, dp) = (t / TAU, dt / TAU);
+    // The slope is ±4 per cycle, so the corners change it
+    // by 8 per cycle: -8 at 0 and +8 at one-half. Convert
+    // to the residual's slope change of 2 per sample.
+    let k = 4.0 * dp;
+    tri(t) - k * poly_blamp(p, dp) + k * poly_blamp(half_turn(p), dp)
+}
+
+// Narrowest allowed pulse, as a fraction of the cycle.
+const MIN_DUTY: f32 = 0.01;
+
+impl<'a> Wave<'a> {
+    fn new(freq: f32, gen: &'a WaveGen, rate: u32) -> Self {
         Self {
             t: 0.0,
-            dt: TAU * freq / SAMPLE_RATE as f32,
-            f: shapefn,
+            dt: TAU * freq / rate as f32,
+            shape: gen.shape,
+            mode: gen.mode,
+            duty: gen.duty,
+            pwm: gen.pwm.as_ref().map(|pwm| PwmState::new(pwm, rate)),
+            noise: Noise::new(gen.shape, gen.next_seed()),
         }
     }
+
+    // Current duty cycle, modulated and then clamped so that
+    // both pulse edges stay at least a sample apart.
+    fn duty(&mut self) -> f32 {
+        let m = self.pwm.as_mut().map(PwmState::next).unwrap_or(0.0);
+        let lim = f32::max(MIN_DUTY, self.dt / TAU);
+        (self.duty + m).clamp(lim, 1.0 - lim)
+    }
 }
 
-impl Iterator for Wave {
+impl Iterator for Wave<'_> {
     type Item = f32;
 
     fn next(&mut self) -> Option<f32> {
+        if let Some(ref mut noise) = self.noise {
+            return noise.next();
+        }
         self.t += self.dt;
         while self.t >= TAU {
             self.t -= TAU;
         }
-        Some((self.f)(self.t))
+        let (t, dt) = (self.t, self.dt);
+        let s = match (self.shape, self.mode) {
+            (WaveShape::Pulse, WaveMode::Raw) => pulse(t, self.duty()),
+            (WaveShape::Pulse, WaveMode::BandLimited) => pulse_bl(t, dt, self.duty()),
+            (WaveShape::Square, WaveMode::BandLimited) => square_bl(t, dt),
+            (WaveShape::Saw, WaveMode::BandLimited) => saw_bl(t, dt),
+            (WaveShape::Tri, WaveMode::BandLimited) => tri_bl(t, dt),
+            (shape, _) => shape.raw(t),
+        };
+        Some(s)
+    }
+}
+
+impl Stream for Wave<'_> {
+    fn release(&mut self) {
+        if let Some(PwmState::Envelope { envelope, .. }) = self.pwm.as_mut() {
+            envelope.release();
+        }
+    }
+
+    fn retrigger(&mut self) {
+        if let Some(PwmState::Envelope { envelope, .. }) = self.pwm.as_mut() {
+            envelope.retrigger();
+        }
     }
 }
 
 pub struct WaveGen {
     shape: WaveShape,
+    mode: WaveMode,
+    /// Pulse duty cycle.
+    duty: f32,
+    /// Pulse-width modulation.
+    pwm: Option<Pwm>,
+    /// Noise seed.
+    seed: u64,
+    /// Notes started so far, to give each noise note its
+    /// own seed.
+    notes: AtomicU64,
 }
 
+/// Default noise seed.
+const SEED: u64 = 0x5eed;
+
 impl WaveGen {
+    /// Make a new band-limited wave generator.
     pub fn new(shape: WaveShape) -> Self {
-        Self { shape }
+        Self::with_mode(shape, WaveMode::BandLimited)
+    }
+
+    /// Make a new wave generator with the given rendering
+    /// mode.
+    pub fn with_mode(shape: WaveShape, mode: WaveMode) -> Self {
+        Self {
+            shape,
+            mode,
+            duty: 0.5,
+            pwm: None,
+            seed: SEED,
+            notes: AtomicU64::new(0),
+        }
+    }
+
+    /// Set the duty cycle of a pulse wave: the fraction of
+    /// the cycle for which it is high.
+    pub fn with_duty(mut self, duty: f32) -> Self {
+        self.duty = duty;
+        self
+    }
+
+    /// Set the pulse-width modulation of a pulse wave.
+    pub fn with_pwm(mut self, pwm: Pwm) -> Self {
+        self.pwm = Some(pwm);
+        self
+    }
+
+    /// Set the noise seed. The `n`th note played gets the
+    /// same noise every time for a given seed.
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self.notes = AtomicU64::new(0);
+        self
+    }
+
+    // Seed for the next note.
+    fn next_seed(&self) -> u64 {
+        let n = self.notes.fetch_add(1, Ordering::Relaxed);
+        Rng::new(self.seed ^ n).next_u64()
     }
 }
 
 impl<'a> Voice<'a> for WaveGen {
-    fn iter_freq(&'a self, freq: f32) -> Box<Signal<'a>> {
-        Box::new(Wave::new(freq, self.shape))
+    fn iter_freq(&'a self, freq: f32, rate: u32) -> Box<Signal<'a>> {
+        Box::new(Wave::new(freq, self, rate))
+    }
+}
+
+// Fraction of the energy of a generated wave that lies
+// outside its harmonics below Nyquist.
+#[cfg(test)]
+fn alias_ratio(freq: f32, gen: &WaveGen, rate: u32) -> f32 {
+    use dsp::{
+        node::{complex::RealToComplex, fft},
+        num_complex::Complex32,
+        runtime::node::ProcessNode,
+    };
+
+    const N: usize = 8192;
+    let buf: Vec<f32> = Wave::new(freq, gen, rate).take(N).collect();
+    let mut csignal = vec![Complex32::default(); N];
+    RealToComplex::new()
+        .process_buffer(&buf, &mut csignal)
+        .unwrap();
+    let mut spectrum = vec![Complex32::default(); N];
+    fft::ForwardFFT::new(N, fft::WindowType::Blackman)
+        .process_buffer(&csignal, &mut spectrum)
+        .unwrap();
+
+    // Blackman main lobe is three bins either side.
+    let bin = rate as f32 / N as f32;
+    let harmonic = |i: usize| {
+        let h = f32::round(i as f32 * bin / freq);
+        h >= 1.0 && f32::abs(i as f32 - h * freq / bin) <= 4.0
+    };
+    let (mut total, mut alias) = (0.0, 0.0);
+    for (i, c) in spectrum[1..N / 2].iter().enumerate() {
+        let e = c.norm_sqr();
+        total += e;
+        if !harmonic(i + 1) {
+            alias += e;
+        }
+    }
+    alias / total
+}
+
+#[test]
+// Check that band-limited waves have little alias energy at
+// a high fundamental, and much less than the raw waves, at
+// common sample rates.
+fn test_alias_energy() {
+    let freq = 4987.0;
+    for rate in [44_100, DEFAULT_SAMPLE_RATE] {
+        for shape in [WaveShape::Square, WaveShape::Saw, WaveShape::Tri] {
+            let raw = alias_ratio(freq, &WaveGen::with_mode(shape, WaveMode::Raw), rate);
+            let bl = alias_ratio(
+                freq,
+                &WaveGen::with_mode(shape, WaveMode::BandLimited),
+                rate,
+            );
+            assert!(bl < 0.01, "{:?} at {}: alias ratio {}", shape, rate, bl);
+            assert!(bl < 0.2 * raw, "{:?}: {} vs raw {}", shape, bl, raw);
+        }
     }
 }
+
+#[test]
+// Check that band-limiting leaves low notes essentially alone.
+fn test_band_limited_low() {
+    let freq = 110.0;
+    for shape in [WaveShape::Square, WaveShape::Saw, WaveShape::Tri] {
+        let (raw, bl) = (
+            WaveGen::with_mode(shape, WaveMode::Raw),
+            WaveGen::with_mode(shape, WaveMode::BandLimited),
+        );
+        let (raw, bl) = (
+            Wave::new(freq, &raw, DEFAULT_SAMPLE_RATE),
+            Wave::new(freq, &bl, DEFAULT_SAMPLE_RATE),
+        );
+        let err: f32 = raw.zip(bl).take(4800).map(|(r, b)| (r - b).abs()).sum();
+        let err = err / 4800.0;
+        assert!(err < 0.01, "{:?}: mean error {}", shape, err);
+    }
+}
+
+#[test]
+// Check that a band-limited narrow pulse does not alias.
+fn test_pulse_alias_energy() {
+    let freq = 4987.0;
+    let raw = WaveGen::with_mode(WaveShape::Pulse, WaveMode::Raw).with_duty(0.3);
+    let bl = WaveGen::new(WaveShape::Pulse).with_duty(0.3);
+    let (raw, bl) = (
+        alias_ratio(freq, &raw, DEFAULT_SAMPLE_RATE),
+        alias_ratio(freq, &bl, DEFAULT_SAMPLE_RATE),
+    );
+    assert!(bl < 0.01, "alias ratio {}", bl);
+    assert!(bl < 0.2 * raw, "{} vs raw {}", bl, raw);
+}
+
+#[test]
+// Check that a pulse has the requested duty cycle, and that
+// its DC offset stays near zero while the width is
+// modulated.
+fn test_pulse_duty() {
+    let period = 100;
+    let freq = DEFAULT_SAMPLE_RATE as f32 / period as f32;
+    let gen = WaveGen::with_mode(WaveShape::Pulse, WaveMode::Raw).with_duty(0.25);
+    let high = Wave::new(freq, &gen, DEFAULT_SAMPLE_RATE)
+        .take(100 * period)
+        .filter(|&s| s > 0.0)
+        .count();
+    assert!(high.abs_diff(25 * period) <= period);
+
+    let pwm = Pwm::Lfo {
+        rate: 2.0,
+        depth: 0.3,
+    };
+    let gen = gen.with_duty(0.5).with_pwm(pwm);
+    let samples: Vec<f32> = Wave::new(freq, &gen, DEFAULT_SAMPLE_RATE)
+        .take(200 * period)
+        .collect();
+    for cycle in samples.chunks(period) {
+        let dc: f32 = cycle.iter().sum::<f32>() / period as f32;
+        assert!(dc.abs() < 0.05, "dc offset {}", dc);
+    }
+}
+
+#[test]
+// Check that noise notes ignore pitch and are reproducible.
+fn test_noise_notes() {
+    let render = |gen: &WaveGen, freq| -> Vec<f32> {
+        Wave::new(freq, gen, DEFAULT_SAMPLE_RATE)
+            .take(1000)
+            .collect()
+    };
+    let gen = WaveGen::new(WaveShape::Pink).with_seed(3);
+    let first = render(&gen, 440.0);
+    let second = render(&gen, 880.0);
+    assert_ne!(first, second);
+    let gen = WaveGen::new(WaveShape::Pink).with_seed(3);
+    assert_eq!(first, render(&gen, 220.0));
+    assert_eq!(second, render(&gen, 110.0));
+}
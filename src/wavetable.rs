@@ -0,0 +1,340 @@
+// Copyright © 2022 Bart Massey
+// [This program is licensed under the "MIT License"]
+// Please see the file LICENSE in the source
+// distribution of this software for license terms.
+
+//! Wavetable synthesis.
+//!
+//! A wavetable is a sequence of single-cycle frames. A note
+//! plays a position between frames, crossfading between the
+//! neighboring frames as the position moves. Each frame is
+//! kept at several band limits ("mip levels"), one per
+//! octave, so that high notes can be played without
+//! aliasing.
+
+use std::error::Error;
+
+use dsp::{node::fft, num_complex::Complex32, runtime::node::ProcessNode};
+
+use crate::*;
+
+/// Length of a wavetable frame in samples. Must be a power
+/// of two.
+pub const FRAME_LEN: usize = 2048;
+
+/// Movement of the wavetable position over the course of a
+/// note. Positions are in frames, and may be fractional.
+#[derive(Debug, Clone, Copy)]
+pub struct Morph {
+    /// Position at the start of the note.
+    pub start: f32,
+    /// Position at the end of the sweep.
+    pub end: f32,
+    /// Sweep time in seconds.
+    pub time: f32,
+}
+
+/// Wavetable voice.
+pub struct Wavetable {
+    /// Band-limited frames, indexed by mip level and then by
+    /// frame. Level `k` has `harmonics(k)` harmonics.
+    tables: Vec<Vec<Vec<f32>>>,
+    /// Position movement during a note.
+    morph: Morph,
+}
+
+// Number of harmonics kept at a given mip level. Level 0
+// keeps everything below the frame Nyquist frequency.
+fn harmonics(level: usize) -> usize {
+    if level == 0 {
+        FRAME_LEN / 2 - 1
+    } else {
+        (FRAME_LEN / 2) >> level
+    }
+}
+
+// Resample a single cycle of arbitrary length to a frame,
+// treating the cycle as periodic.
+fn to_frame(cycle: &[f32]) -> Vec<f32> {
+    let n = cycle.len();
+    if n == FRAME_LEN {
+        return cycle.to_vec();
+    }
+    // Three periods, so that the filter always has data.
+    let periodic = cycle.repeat(3);
+    // Rates are in cycles per input sample.
+    let scale = n as f32 / FRAME_LEN as f32;
+    let fmax = 0.5 * f32::min(1.0, 1.0 / scale);
+    (0..FRAME_LEN)
+        .map(|i| {
+            let x = n as f32 + i as f32 * scale;
+            resamp(x, &periodic, fmax, 1.0, RESAMP_WIDTH)
+        })
+        .collect()
+}
+
+// Make the band-limited versions of a frame, one per mip
+// level, by discarding harmonics from its spectrum.
+fn mipmap(frame: &[f32]) -> Vec<Vec<f32>> {
+    let csignal: Vec<Complex32> = frame.iter().map(|&s| Complex32::new(s, 0.0)).collect();
+    let mut spectrum = vec![Complex32::default(); FRAME_LEN];
+    fft::ForwardFFT::new(FRAME_LEN, fft::WindowType::Rectangular)
+        .process_buffer(&csignal, &mut spectrum)
+        .unwrap();
+    let mut inverse = fft::InverseFFT::new(FRAME_LEN);
+
+    let mut levels = Vec::new();
+    let mut level = 0;
+    loop {
+        let h = harmonics(level);
+        // Keep harmonics 1..=h and their mirror images: DC
+        // goes too.
+        let mut limited = spectrum.clone();
+        limited[0] = Complex32::default();
+        for c in &mut limited[h + 1..FRAME_LEN - h] {
+            *c = Complex32::default();
+        }
+        let mut signal = vec![Complex32::default(); FRAME_LEN];
+        inverse.process_buffer(&limited, &mut signal).unwrap();
+        levels.push(signal.iter().map(|c| c.re / FRAME_LEN as f32).collect());
+        if h == 1 {
+            return levels;
+        }
+        level += 1;
+    }
+}
+
+impl Wavetable {
+    /// Make a wavetable from single-cycle frames of any
+    /// length. Each frame is normalized to unit peak. The
+    /// default morph sweeps from the first to the last frame
+    /// over one second.
+    pub fn new(cycles: &[Vec<f32>]) -> Self {
+        assert!(!cycles.is_empty(), "wavetable needs frames");
+        let mut tables = Vec::new();
+        for cycle in cycles {
+            let mut frame = to_frame(cycle);
+            let peak = frame.iter().fold(0.0f32, |m, s| m.max(s.abs()));
+            if peak > 0.0 {
+                for s in &mut frame {
+                    *s /= peak;
+                }
+            }
+            for (level, f) in mipmap(&frame).into_iter().enumerate() {
+                if level >= tables.len() {
+                    tables.push(Vec::new());
+                }
+                tables[level].push(f);
+            }
+        }
+        let morph = Morph {
+            start: 0.0,
+            end: (cycles.len() - 1) as f32,
+            time: 1.0,
+        };
+        Self { tables, morph }
+    }
+
+    /// Make a wavetable with one frame for each of the given
+    /// wave shapes.
+    pub fn from_shapes(shapes: &[WaveShape]) -> Self {
+        let cycles: Vec<Vec<f32>> = shapes
+            .iter()
+            .map(|shape| {
+                (0..FRAME_LEN)
+                    .map(|i| shape.raw(std::f32::consts::TAU * i as f32 / FRAME_LEN as f32))
+                    .collect()
+            })
+            .collect();
+        Self::new(&cycles)
+    }
+
+    /// Make a wavetable from WAV files, each containing a
+    /// single cycle.
+    pub fn load<P>(names: &[P]) -> Result<Self, Box<dyn Error>>
+    where
+        P: AsRef<std::path::Path>,
+    {
+        // A single cycle is resampled to fit the frame
+        // anyway, so its sample rate does not matter.
+        let cycles = names
+            .iter()
+            .map(|name| {
+                let file = std::io::BufReader::new(std::fs::File::open(name)?);
+                Ok(read_wav(file, None)?.0)
+            })
+            .collect::<Result<Vec<_>, Box<dyn Error>>>()?;
+        Ok(Self::new(&cycles))
+    }
+
+    /// Set the position movement during a note.
+    pub fn with_morph(mut self, morph: Morph) -> Self {
+        self.morph = morph;
+        self
+    }
+
+    /// Number of frames in the table.
+    pub fn nframes(&self) -> usize {
+        self.tables[0].len()
+    }
+
+    // Lowest mip level with no harmonics above Nyquist at
+    // the given frequency and sample rate.
+    fn level_for(&self, freq: f32, rate: u32) -> usize {
+        let nyquist = rate as f32 / 2.0;
+        let last = self.tables.len() - 1;
+        (0..last)
+            .find(|&k| harmonics(k) as f32 * freq <= nyquist)
+            .unwrap_or(last)
+    }
+}
+
+/// A sounding wavetable note.
+struct TableNote<'a> {
+    /// Frames at the mip level for this note.
+    frames: &'a [Vec<f32>],
+    /// Phase in frame samples.
+    x: f32,
+    /// Phase increment per sample.
+    incr: f32,
+    /// Current frame position.
+    pos: f32,
+    /// Position increment per sample.
+    dpos: f32,
+    /// Final frame position.
+    end: f32,
+}
+
+impl<'a> TableNote<'a> {
+    fn new(table: &'a Wavetable, freq: f32, rate: u32) -> Self {
+        let frames = &table.tables[table.level_for(freq, rate)];
+        let last = (frames.len() - 1) as f32;
+        let Morph { start, end, time } = table.morph;
+        let (start, end) = (start.clamp(0.0, last), end.clamp(0.0, last));
+        let (pos, dpos) = if time > 0.0 {
+            (start, (end - start) / (time * rate as f32))
+        } else {
+            (end, 0.0)
+        };
+        Self {
+            frames,
+            x: 0.0,
+            incr: freq * FRAME_LEN as f32 / rate as f32,
+            pos,
+            dpos,
+            end,
+        }
+    }
+}
+
+// Linearly interpolated frame value at fractional index `x`.
+fn lerp(frame: &[f32], x: f32) -> f32 {
+    let i = x as usize;
+    let f = x - i as f32;
+    let (s0, s1) = (frame[i], frame[(i + 1) % FRAME_LEN]);
+    s0 + f * (s1 - s0)
+}
+
+impl Iterator for TableNote<'_> {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        let i = self.pos as usize;
+        let f = self.pos - i as f32;
+        let s0 = lerp(&self.frames[i], self.x);
+        let s = if f > 0.0 {
+            let s1 = lerp(&self.frames[i + 1], self.x);
+            s0 + f * (s1 - s0)
+        } else {
+            s0
+        };
+
+        self.x += self.incr;
+        while self.x >= FRAME_LEN as f32 {
+            self.x -= FRAME_LEN as f32;
+        }
+        if self.pos != self.end {
+            self.pos += self.dpos;
+            if (self.dpos > 0.0 && self.pos > self.end) || (self.dpos < 0.0 && self.pos < self.end)
+            {
+                self.pos = self.end;
+            }
+        }
+        Some(s)
+    }
+}
+
+impl Stream for TableNote<'_> {}
+
+impl<'a> Voice<'a> for Wavetable {
+    fn iter_freq(&'a self, freq: f32, rate: u32) -> Box<Signal<'a>> {
+        Box::new(TableNote::new(self, freq, rate))
+    }
+}
+
+#[test]
+// Check that a sine table plays a sine wave.
+fn test_wavetable_sine() {
+    let table = Wavetable::from_shapes(&[WaveShape::Sine]);
+    let freq = 440.0;
+    for (i, s) in table
+        .iter_freq(freq, DEFAULT_SAMPLE_RATE)
+        .take(1000)
+        .enumerate()
+    {
+        let t = std::f32::consts::TAU * freq * i as f32 / DEFAULT_SAMPLE_RATE as f32;
+        assert!((s - t.sin()).abs() < 1.0e-3);
+    }
+}
+
+#[test]
+// Check that high notes use a table with only the harmonics
+// of a sawtooth that fit below Nyquist.
+fn test_wavetable_mipmap() {
+    let table = Wavetable::from_shapes(&[WaveShape::Saw]);
+    let freq = 5000.0;
+    let level = table.level_for(freq, DEFAULT_SAMPLE_RATE);
+    let h = harmonics(level);
+    assert!(h as f32 * freq <= DEFAULT_SAMPLE_RATE as f32 / 2.0);
+    assert!(2.0 * h as f32 * freq > DEFAULT_SAMPLE_RATE as f32 / 2.0);
+    for (i, &s) in table.tables[level][0].iter().enumerate() {
+        let t = std::f32::consts::TAU * i as f32 / FRAME_LEN as f32;
+        let partial: f32 = (1..=h)
+            .map(|k| 2.0 / (std::f32::consts::PI * k as f32) * f32::sin(k as f32 * t))
+            .sum();
+        assert!((s - partial).abs() < 1.0e-2);
+    }
+}
+
+#[test]
+// Check that a morph ends up on its final frame.
+fn test_wavetable_morph() {
+    let table = Wavetable::from_shapes(&[WaveShape::Sine, WaveShape::Tri]).with_morph(Morph {
+        start: 0.0,
+        end: 1.0,
+        time: 0.01,
+    });
+    let tri = Wavetable::from_shapes(&[WaveShape::Tri]);
+    let n = DEFAULT_SAMPLE_RATE as usize / 100;
+    let morphed = table
+        .iter_freq(440.0, DEFAULT_SAMPLE_RATE)
+        .skip(n)
+        .take(1000);
+    let target = tri.iter_freq(440.0, DEFAULT_SAMPLE_RATE).skip(n).take(1000);
+    for (s, t) in morphed.zip(target) {
+        assert!((s - t).abs() < 1.0e-4);
+    }
+}
+
+#[test]
+// Check that odd-length cycles are resampled to frames.
+fn test_wavetable_resample() {
+    let cycle: Vec<f32> = (0..100)
+        .map(|i| f32::sin(std::f32::consts::TAU * i as f32 / 100.0))
+        .collect();
+    let frame = to_frame(&cycle);
+    for (i, &s) in frame.iter().enumerate() {
+        let t = std::f32::consts::TAU * i as f32 / FRAME_LEN as f32;
+        assert!((s - t.sin()).abs() < 0.05);
+    }
+}
@@ -4,27 +4,425 @@
 // distribution of this software for license terms.
 
 //! Synthesizer sample file reader.
+//!
+//! WAV files of 8, 16, 24 or 32 bit integer samples or 32
+//! bit float samples can be read, with any number of
+//! channels and at any sample rate. Sampler metadata in
+//! `smpl` and `inst` chunks is read too, when present.
 
 use std::error::Error;
-use std::io::{self, ErrorKind};
+use std::fmt;
+use std::io::Read;
+use std::ops::{Range, RangeInclusive};
+
+use crate::*;
+
+/// Reasons a sample file cannot be used.
+#[derive(Debug)]
+pub enum SampleError {
+    /// The file could not be read as a WAV file.
+    Wav(hound::Error),
+    /// The sample encoding is not one we handle.
+    Format {
+        bits: u16,
+        format: hound::SampleFormat,
+    },
+    /// The requested channel is not in the file.
+    Channel { channel: usize, channels: u16 },
+    /// The file holds no samples.
+    Empty,
+    /// A sampler metadata chunk is malformed.
+    Metadata(&'static str),
+}
+
+impl fmt::Display for SampleError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SampleError::Wav(e) => write!(f, "cannot read WAV file: {}", e),
+            SampleError::Format { bits, format } => {
+                let format = match format {
+                    hound::SampleFormat::Int => "integer",
+                    hound::SampleFormat::Float => "float",
+                };
+                write!(f, "unsupported WAV format: {} bit {}", bits, format)
+            }
+            SampleError::Channel { channel, channels } => write!(
+                f,
+                "no channel {} in WAV file: channels are 0 to {}",
+                channel,
+                channels - 1
+            ),
+            SampleError::Empty => write!(f, "WAV file has no samples"),
+            SampleError::Metadata(chunk) => write!(f, "malformed WAV {} chunk", chunk),
+        }
+    }
+}
+
+impl Error for SampleError {}
+
+impl From<hound::Error> for SampleError {
+    fn from(e: hound::Error) -> Self {
+        SampleError::Wav(e)
+    }
+}
+
+/// Read WAV data and return it as a buffer of normalized
+/// (float) samples along with its sample rate. With a
+/// `channel` (counting from 0) only that channel is kept;
+/// otherwise all channels are mixed down.
+pub fn read_wav<R: Read>(
+    reader: R,
+    channel: Option<usize>,
+) -> Result<(Vec<f32>, u32), SampleError> {
+    let mut wavfile = hound::WavReader::new(reader)?;
+    let ws = wavfile.spec();
+    let channels = usize::from(ws.channels);
+    if let Some(channel) = channel {
+        if channel >= channels {
+            return Err(SampleError::Channel {
+                channel,
+                channels: ws.channels,
+            });
+        }
+    }
+
+    // Get the interleaved signal.
+    let interleaved: Vec<f32> = match (ws.sample_format, ws.bits_per_sample) {
+        (hound::SampleFormat::Int, bits @ (8 | 16 | 24 | 32)) => {
+            let scale = 1.0 / (1u64 << (bits - 1)) as f32;
+            wavfile
+                .samples::<i32>()
+                .map(|s| s.map(|s| s as f32 * scale))
+                .collect::<Result<_, _>>()?
+        }
+        (hound::SampleFormat::Float, 32) => wavfile.samples::<f32>().collect::<Result<_, _>>()?,
+        (format, bits) => return Err(SampleError::Format { bits, format }),
+    };
+
+    // Pick out or mix down the channels. A trailing
+    // partial frame is dropped.
+    let signal: Vec<f32> = interleaved
+        .chunks_exact(channels)
+        .map(|frame| match channel {
+            Some(c) => frame[c],
+            None => frame.iter().sum::<f32>() / channels as f32,
+        })
+        .collect();
+    if signal.is_empty() {
+        return Err(SampleError::Empty);
+    }
+    Ok((signal, ws.sample_rate))
+}
+
+/// Sampler metadata from a WAV file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SampleMeta {
+    /// MIDI key at which the sample sounds at its recorded
+    /// pitch. May be fractional.
+    pub root: Option<f32>,
+    /// Sample indices of the first forward loop.
+    pub sustain: Option<Range<usize>>,
+    /// Keys the sample is meant to be played on.
+    pub keys: Option<RangeInclusive<u8>>,
+    /// Velocities the sample is meant to be played at.
+    pub velocities: Option<RangeInclusive<u8>>,
+    /// Gain to apply to the sample in dB.
+    pub gain: f32,
+}
+
+impl Default for SampleMeta {
+    fn default() -> Self {
+        Self {
+            root: None,
+            sustain: None,
+            keys: None,
+            velocities: None,
+            gain: 0.0,
+        }
+    }
+}
+
+impl SampleMeta {
+    /// Move the sample positions to match samples
+    /// resampled from one rate to another. A loop is kept
+    /// at least one sample long; an empty one is dropped.
+    pub fn resampled(mut self, from: u32, to: u32) -> Self {
+        let scale = |i: usize| (i as u64 * u64::from(to) / u64::from(from)) as usize;
+        self.sustain = self
+            .sustain
+            .filter(|l| !l.is_empty())
+            .map(|l| scale(l.start)..usize::max(scale(l.end), scale(l.start) + 1));
+        self
+    }
+}
+
+// Little-endian 32-bit word at the given offset.
+fn le_u32(data: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes([data[at], data[at + 1], data[at + 2], data[at + 3]])
+}
+
+/// Find the sampler metadata in WAV data. A file without
+/// `smpl` or `inst` chunks gives the default metadata. The
+/// `smpl` root key is preferred to the `inst` one.
+pub fn read_meta(data: &[u8]) -> Result<SampleMeta, SampleError> {
+    if data.len() < 12 || &data[..4] != b"RIFF" || &data[8..12] != b"WAVE" {
+        return Err(SampleError::Wav(hound::Error::FormatError(
+            "not a WAV file",
+        )));
+    }
+    let mut meta = SampleMeta::default();
+    let mut inst_root = None;
+    let mut pos = 12;
+    while pos + 8 <= data.len() {
+        let tag = &data[pos..pos + 4];
+        let len = le_u32(data, pos + 4) as usize;
+        let body = &data[pos + 8..];
+        // A chunk running off the end is left to the
+        // sample reader to complain about.
+        let body = &body[..len.min(body.len())];
+        match tag {
+            b"smpl" => {
+                if body.len() < 36 {
+                    return Err(SampleError::Metadata("smpl"));
+                }
+                let key = le_u32(body, 12);
+                if key < 128 {
+                    // The fraction is of a semitone up.
+                    let fraction = le_u32(body, 16) as f32 / 4_294_967_296.0;
+                    meta.root = Some(key as f32 + fraction);
+                }
+                let nloops = le_u32(body, 28) as usize;
+                if (body.len() - 36) / 24 < nloops {
+                    return Err(SampleError::Metadata("smpl"));
+                }
+                // Forward loops only. The end is inclusive.
+                meta.sustain = body[36..36 + 24 * nloops]
+                    .chunks_exact(24)
+                    .find(|l| le_u32(l, 4) == 0)
+                    .map(|l| le_u32(l, 8) as usize..le_u32(l, 12) as usize + 1);
+            }
+            b"inst" => {
+                if body.len() < 7 {
+                    return Err(SampleError::Metadata("inst"));
+                }
+                let cents = body[1] as i8;
+                let gain = body[2] as i8;
+                let [lo_key, hi_key, lo_vel, hi_vel] = [body[3], body[4], body[5], body[6]];
+                if body[0] < 128 {
+                    // Fine tuning raises the pitch played,
+                    // so the recorded pitch is that much
+                    // below the key.
+                    inst_root = Some(body[0] as f32 - cents as f32 / 100.0);
+                }
+                meta.gain = gain as f32;
+                if lo_key <= hi_key && hi_key < 128 {
+                    meta.keys = Some(lo_key..=hi_key);
+                }
+                if lo_vel <= hi_vel && hi_vel < 128 {
+                    meta.velocities = Some(lo_vel..=hi_vel);
+                }
+            }
+            _ => (),
+        }
+        // Chunks are padded to even length.
+        pos = pos.saturating_add(8 + len + (len & 1));
+    }
+    meta.root = meta.root.or(inst_root);
+    Ok(meta)
+}
 
 /// Read a sample file and return it as a buffer of
-/// normalized (float) samples.
-pub fn get_sample<P>(name: P) -> Result<Vec<f32>, Box<dyn Error>>
+/// normalized (float) samples, along with its sample rate
+/// and sampler metadata. See [read_wav] for `channel`. A
+/// loop that does not fit the samples is dropped.
+pub fn read_sample<P>(
+    name: P,
+    channel: Option<usize>,
+) -> Result<(Vec<f32>, u32, SampleMeta), Box<dyn Error>>
 where
     P: AsRef<std::path::Path>,
 {
-    // Open and check the file.
-    let mut wavfile = hound::WavReader::open(name.as_ref())?;
-    let ws = wavfile.spec();
-    if ws.channels != 1 || ws.bits_per_sample != 16 || ws.sample_rate != crate::SAMPLE_RATE {
-        return Err(Box::new(io::Error::from(ErrorKind::InvalidData)));
+    let data = std::fs::read(name)?;
+    let (signal, file_rate) = read_wav(&data[..], channel)?;
+    let mut meta = read_meta(&data)?;
+    meta.sustain = meta
+        .sustain
+        .filter(|l| !l.is_empty() && l.end <= signal.len());
+    Ok((signal, file_rate, meta))
+}
+
+/// Read a sample file as with [read_sample], and resample
+/// it to the given sample rate, adjusting the metadata to
+/// match.
+pub fn get_sample<P>(
+    name: P,
+    channel: Option<usize>,
+    rate: u32,
+) -> Result<(Vec<f32>, SampleMeta), Box<dyn Error>>
+where
+    P: AsRef<std::path::Path>,
+{
+    let (signal, file_rate, meta) = read_sample(name, channel)?;
+    Ok((
+        resample(&signal, file_rate, rate),
+        meta.resampled(file_rate, rate),
+    ))
+}
+
+#[cfg(test)]
+// Append a chunk to in-memory WAV data, fixing up the RIFF
+// length.
+fn add_chunk(data: &mut Vec<u8>, tag: &[u8; 4], body: &[u8]) {
+    data.extend(tag);
+    data.extend((body.len() as u32).to_le_bytes());
+    data.extend(body);
+    if body.len() % 2 == 1 {
+        data.push(0);
     }
+    let riff = (data.len() - 8) as u32;
+    data[4..8].copy_from_slice(&riff.to_le_bytes());
+}
 
-    // Get the signal.
-    let signal = wavfile
-        .samples::<i16>()
-        .map(|s| s.unwrap() as f32 / 32768.0f32)
-        .collect();
-    Ok(signal)
+#[cfg(test)]
+// Make an in-memory WAV file from frames of full-scale
+// fractions.
+fn wav(spec: hound::WavSpec, frames: &[&[f32]]) -> Vec<u8> {
+    let mut out = std::io::Cursor::new(Vec::new());
+    let mut writer = hound::WavWriter::new(&mut out, spec).unwrap();
+    let bits = spec.bits_per_sample;
+    for &x in frames.iter().flat_map(|f| f.iter()) {
+        match spec.sample_format {
+            hound::SampleFormat::Float => writer.write_sample(x).unwrap(),
+            hound::SampleFormat::Int => {
+                let full = (1i64 << (bits - 1)) as f32;
+                writer.write_sample((x * full) as i32).unwrap()
+            }
+        }
+    }
+    writer.finalize().unwrap();
+    out.into_inner()
+}
+
+#[test]
+// Check that every supported sample format reads back the
+// same, and that channels are selected or mixed.
+fn test_read_wav() {
+    let frames: &[&[f32]] = &[&[0.5, -0.25], &[-0.5, 0.0], &[0.0, 0.25]];
+    let formats = [
+        (8, hound::SampleFormat::Int),
+        (16, hound::SampleFormat::Int),
+        (24, hound::SampleFormat::Int),
+        (32, hound::SampleFormat::Int),
+        (32, hound::SampleFormat::Float),
+    ];
+    for (bits, sample_format) in formats {
+        let spec = hound::WavSpec {
+            channels: 2,
+            sample_rate: 44_100,
+            bits_per_sample: bits,
+            sample_format,
+        };
+        let data = wav(spec, frames);
+        let (left, rate) = read_wav(&data[..], Some(0)).unwrap();
+        assert_eq!(44_100, rate);
+        assert_eq!(vec![0.5, -0.5, 0.0], left);
+        let (right, _) = read_wav(&data[..], Some(1)).unwrap();
+        assert_eq!(vec![-0.25, 0.0, 0.25], right);
+        let (mix, _) = read_wav(&data[..], None).unwrap();
+        assert_eq!(vec![0.125, -0.25, 0.125], mix);
+    }
+}
+
+#[test]
+// Check that unusable files give the right errors.
+fn test_read_wav_errors() {
+    let spec = hound::WavSpec {
+        channels: 2,
+        sample_rate: DEFAULT_SAMPLE_RATE,
+        bits_per_sample: 16,
+        sample_format: hound::SampleFormat::Int,
+    };
+    let data = wav(spec, &[&[0.0, 0.0]]);
+    assert!(matches!(
+        read_wav(&data[..], Some(2)),
+        Err(SampleError::Channel {
+            channel: 2,
+            channels: 2
+        })
+    ));
+    let empty = wav(spec, &[]);
+    assert!(matches!(
+        read_wav(&empty[..], None),
+        Err(SampleError::Empty)
+    ));
+    assert!(matches!(
+        read_wav(&b"RIFF"[..], None),
+        Err(SampleError::Wav(_))
+    ));
+}
+
+#[test]
+// Check that smpl and inst chunks are found, and that smpl
+// wins for the root key.
+fn test_read_meta() {
+    let spec = hound::WavSpec {
+        channels: 1,
+        sample_rate: DEFAULT_SAMPLE_RATE,
+        bits_per_sample: 16,
+        sample_format: hound::SampleFormat::Int,
+    };
+    let frame: &[f32] = &[0.0];
+    let mut data = wav(spec, &[frame; 10]);
+    assert_eq!(SampleMeta::default(), read_meta(&data).unwrap());
+
+    add_chunk(
+        &mut data,
+        b"inst",
+        &[60, (-50i8) as u8, (-6i8) as u8, 48, 72, 1, 100],
+    );
+    let meta = read_meta(&data).unwrap();
+    assert_eq!(Some(60.5), meta.root);
+    assert_eq!(Some(48..=72), meta.keys);
+    assert_eq!(Some(1..=100), meta.velocities);
+    assert_eq!(-6.0, meta.gain);
+
+    let mut smpl = Vec::new();
+    // Manufacturer, product, period, key, fraction (a
+    // quarter semitone), SMPTE format and offset, loops,
+    // extra data.
+    for word in [0, 0, 20833, 57, 1 << 30, 0, 0, 2, 0] {
+        smpl.extend(u32::to_le_bytes(word));
+    }
+    // A ping-pong loop, then a forward loop.
+    for word in [0, 1, 0, 9, 0, 0, 1, 0, 2, 7, 0, 0] {
+        smpl.extend(u32::to_le_bytes(word));
+    }
+    add_chunk(&mut data, b"smpl", &smpl);
+    let meta = read_meta(&data).unwrap();
+    assert_eq!(Some(57.25), meta.root);
+    assert_eq!(Some(2..8), meta.sustain);
+    // The samples are still readable.
+    assert_eq!(10, read_wav(&data[..], None).unwrap().0.len());
+
+    add_chunk(&mut data, b"smpl", &smpl[..20]);
+    assert!(matches!(
+        read_meta(&data),
+        Err(SampleError::Metadata("smpl"))
+    ));
+}
+
+#[test]
+// Check that loop points follow a rate change without
+// collapsing.
+fn test_meta_resampled() {
+    let meta = |sustain| SampleMeta {
+        sustain: Some(sustain),
+        ..SampleMeta::default()
+    };
+    assert_eq!(
+        Some(50..150),
+        meta(100..300).resampled(96_000, 48_000).sustain
+    );
+    assert_eq!(Some(1..2), meta(3..4).resampled(96_000, 44_100).sustain);
+    assert_eq!(None, meta(4..4).resampled(96_000, 44_100).sustain);
 }